@@ -30,8 +30,10 @@
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::panic::{RefUnwindSafe, UnwindSafe};
 use std::sync::Mutex;
 use std::{fmt, io};
+use time::OffsetDateTime;
 
 use slog::KV;
 
@@ -41,11 +43,12 @@
     static TL_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(128))
 }
 
-type SysLogger = syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>;
+type SysLogger = syslog::Logger<syslog::LoggerBackend, SyslogFormatter>;
 
 #[inline]
 fn handle_syslog_error(e: syslog::Error) -> io::Error {
-    Error::new(ErrorKind::Other, e.to_string())
+    let causes: Vec<String> = e.iter().map(|cause| cause.to_string()).collect();
+    Error::new(ErrorKind::Other, causes.join(": "))
 }
 
 fn log_with_level(
@@ -64,6 +67,13 @@ fn log_with_level(
     err.map_err(handle_syslog_error)
 }
 
+fn process_name() -> String {
+    let path = std::env::current_exe().unwrap_or_else(|_| PathBuf::new());
+    path.file_name()
+        .map(|file| file.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
 /// Create a formatter with runtime metadata filled in.
 ///
 /// This follows ``get_process_info()`` in the syslog crate to some extent
@@ -71,28 +81,167 @@ fn log_with_level(
 fn syslog_format3164(
     facility: syslog::Facility,
     hostname: Option<String>,
+    process: Option<String>,
 ) -> syslog::Formatter3164 {
-    let path = std::env::current_exe().unwrap_or_else(|_| PathBuf::new());
-    let process = path
-        .file_name()
-        .map(|file| file.to_string_lossy().into_owned())
-        .unwrap_or_default();
-
     syslog::Formatter3164 {
         facility,
         hostname,
-        process,
+        process: process.unwrap_or_else(process_name),
         pid: std::process::id() as i32,
     }
 }
 
+/// Create the message header for `format` with runtime metadata filled in.
+fn syslog_header(
+    format: &dyn SyslogFormat,
+    facility: syslog::Facility,
+    hostname: Option<String>,
+    process: Option<String>,
+) -> SyslogHeader {
+    match format.rfc5424() {
+        Some(format5424) => SyslogHeader::Rfc5424(Header5424 {
+            facility,
+            hostname,
+            app_name: format5424
+                .app_name
+                .clone()
+                .or(process)
+                .unwrap_or_else(process_name),
+            pid: std::process::id(),
+            msgid: format5424.msgid.clone(),
+        }),
+        None => SyslogHeader::Rfc3164(syslog_format3164(facility, hostname, process)),
+    }
+}
+
+/// Header and framing of the syslog messages sent by a `Streamer3164`
+#[derive(Clone)]
+pub struct SyslogFormatter {
+    /// Message header
+    pub header: SyslogHeader,
+    /// Terminate every message with LF and flush it (RFC 6587 non-transparent framing).
+    /// Required on stream transports like TCP
+    pub framing: bool,
+}
+
+impl From<syslog::Formatter3164> for SyslogFormatter {
+    fn from(formatter: syslog::Formatter3164) -> Self {
+        SyslogFormatter {
+            header: SyslogHeader::Rfc3164(formatter),
+            framing: false,
+        }
+    }
+}
+
+impl<T: fmt::Display> syslog::LogFormat<T> for SyslogFormatter {
+    fn format<W: io::Write>(
+        &self,
+        w: &mut W,
+        severity: syslog::Severity,
+        message: T,
+    ) -> syslog::Result<()> {
+        self.header.format(w, severity, message)?;
+        if self.framing {
+            w.write_all(b"\n")?;
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Header of the syslog messages
+#[derive(Clone)]
+pub enum SyslogHeader {
+    /// Header defined in RFC 3164
+    Rfc3164(syslog::Formatter3164),
+    /// Header defined in RFC 5424
+    Rfc5424(Header5424),
+}
+
+impl<T: fmt::Display> syslog::LogFormat<T> for SyslogHeader {
+    fn format<W: io::Write>(
+        &self,
+        w: &mut W,
+        severity: syslog::Severity,
+        message: T,
+    ) -> syslog::Result<()> {
+        match self {
+            SyslogHeader::Rfc3164(formatter) => formatter.format(w, severity, message),
+            SyslogHeader::Rfc5424(header) => header.format(w, severity, message),
+        }
+    }
+}
+
+/// RFC 5424 header fields
+///
+/// `hostname` and `msgid` are sent as NILVALUE (`-`) when not set.
+#[derive(Clone, Debug)]
+pub struct Header5424 {
+    /// Syslog facility
+    pub facility: syslog::Facility,
+    /// HOSTNAME field
+    pub hostname: Option<String>,
+    /// APP-NAME field
+    pub app_name: String,
+    /// PROCID field
+    pub pid: u32,
+    /// MSGID field
+    pub msgid: Option<String>,
+}
+
+impl<T: fmt::Display> syslog::LogFormat<T> for Header5424 {
+    fn format<W: io::Write>(
+        &self,
+        w: &mut W,
+        severity: syslog::Severity,
+        message: T,
+    ) -> syslog::Result<()> {
+        let now = OffsetDateTime::now_utc();
+
+        // A single write per message, the unix and UDP backends send one datagram per write
+        write!(
+            w,
+            "<{}>1 {:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z {} {} {} {} {}",
+            self.facility as u8 | severity as u8,
+            now.year(),
+            now.month() as u8,
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+            now.microsecond(),
+            header_field(self.hostname.as_deref().unwrap_or_default(), 255),
+            header_field(&self.app_name, 48),
+            self.pid,
+            header_field(self.msgid.as_deref().unwrap_or_default(), 32),
+            message
+        )?;
+        Ok(())
+    }
+}
+
+/// RFC 5424 header fields are printable US-ASCII without spaces, empty fields are NILVALUE
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String = value
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '_' })
+        .take(max_len)
+        .collect();
+
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
 /// Drain formatting records and writing them to a syslog ``Logger`
 ///
 /// Uses mutex to serialize writes.
 /// TODO: Add one that does not serialize?
 pub struct Streamer3164 {
     io: Mutex<Box<SysLogger>>,
-    format: Format3164,
+    format: Box<dyn SyslogFormat>,
     level: Level,
 }
 
@@ -135,9 +284,18 @@ fn get_default_level() -> Level {
 impl Streamer3164 {
     /// Create new syslog ``Streamer` using given `format` and logging level.
     pub fn new_with_level(logger: Box<SysLogger>, level: Level) -> Self {
+        Self::new_with_format(logger, level, Box::new(Format3164::new()))
+    }
+
+    /// Create new syslog ``Streamer` using given message `format` and logging level.
+    pub fn new_with_format(
+        logger: Box<SysLogger>,
+        level: Level,
+        format: Box<dyn SyslogFormat>,
+    ) -> Self {
         Streamer3164 {
             io: Mutex::new(logger),
-            format: Format3164::new(),
+            format,
             level,
         }
     }
@@ -178,6 +336,22 @@ fn log(&self, info: &Record, logger_values: &OwnedKVList) -> io::Result<()> {
     }
 }
 
+/// Formatter of the syslog message body
+pub trait SyslogFormat: Send + Sync + UnwindSafe + RefUnwindSafe {
+    /// Write the message body of `record` to `io`
+    fn format(
+        &self,
+        io: &mut dyn io::Write,
+        record: &Record,
+        logger_kv: &OwnedKVList,
+    ) -> io::Result<()>;
+
+    /// RFC 5424 settings of the format. Messages are sent with an RFC 3164 header when `None`
+    fn rfc5424(&self) -> Option<&Format5424> {
+        None
+    }
+}
+
 /// Formatter to format defined in RFC 3164
 #[derive(Default)]
 pub struct Format3164;
@@ -187,7 +361,9 @@ impl Format3164 {
     pub fn new() -> Self {
         Format3164
     }
+}
 
+impl SyslogFormat for Format3164 {
     fn format(
         &self,
         io: &mut dyn io::Write,
@@ -205,6 +381,125 @@ fn format(
     }
 }
 
+/// Formatter to format defined in RFC 5424
+///
+/// slog key-values are sent as parameters of a single structured-data element.
+pub struct Format5424 {
+    app_name: Option<String>,
+    msgid: Option<String>,
+    sd_id: String,
+}
+
+impl Default for Format5424 {
+    fn default() -> Self {
+        Format5424 {
+            app_name: None,
+            msgid: None,
+            sd_id: "slog@32473".to_string(),
+        }
+    }
+}
+
+impl Format5424 {
+    /// Create new `Format5424`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// APP-NAME of the messages. Name of the executable by default
+    pub fn app_name<S: AsRef<str>>(self, app_name: S) -> Self {
+        let mut s = self;
+        s.app_name = Some(app_name.as_ref().to_string());
+        s
+    }
+
+    /// MSGID of the messages. NILVALUE by default
+    pub fn msgid<S: AsRef<str>>(self, msgid: S) -> Self {
+        let mut s = self;
+        s.msgid = Some(msgid.as_ref().to_string());
+        s
+    }
+
+    /// SD-ID of the structured-data element carrying the key-values. `slog@32473` by default
+    pub fn sd_id<S: AsRef<str>>(self, sd_id: S) -> Self {
+        let mut s = self;
+        s.sd_id = sd_name(sd_id.as_ref());
+        s
+    }
+}
+
+impl SyslogFormat for Format5424 {
+    fn format(
+        &self,
+        io: &mut dyn io::Write,
+        record: &Record,
+        logger_kv: &OwnedKVList,
+    ) -> io::Result<()> {
+        let mut ser = StructuredDataSerializer::new(Vec::new());
+        {
+            logger_kv.serialize(record, &mut ser)?;
+            record.kv().serialize(record, &mut ser)?;
+        }
+
+        if ser.io.is_empty() {
+            write!(io, "-")?;
+        } else {
+            write!(io, "[{}", self.sd_id)?;
+            io.write_all(&ser.io)?;
+            write!(io, "]")?;
+        }
+        write!(io, " {}", record.msg())
+    }
+
+    fn rfc5424(&self) -> Option<&Format5424> {
+        Some(self)
+    }
+}
+
+/// SD-NAME is printable US-ASCII except `=`, ` `, `]` and `"`, at most 32 characters
+fn sd_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '=' | ']' | '"' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect();
+
+    if name.is_empty() {
+        "_".to_string()
+    } else {
+        name
+    }
+}
+
+/// Structured-data parameter serializer
+struct StructuredDataSerializer<W: io::Write> {
+    io: W,
+}
+
+impl<W: io::Write> StructuredDataSerializer<W> {
+    fn new(io: W) -> Self {
+        StructuredDataSerializer { io }
+    }
+}
+
+impl<W: io::Write> slog::Serializer for StructuredDataSerializer<W> {
+    fn emit_arguments(&mut self, key: &str, val: &fmt::Arguments) -> slog::Result {
+        write!(self.io, " {}=\"", sd_name(key))?;
+        for c in val.to_string().chars() {
+            match c {
+                '"' | '\\' | ']' => write!(self.io, "\\{}", c)?,
+                c => write!(self.io, "{}", c)?,
+            }
+        }
+        write!(self.io, "\"")?;
+        Ok(())
+    }
+}
+
 /// Key-Separator-Value serializer
 struct KeyValueSerializer<W: io::Write> {
     io: W,
@@ -243,6 +538,8 @@ pub struct SyslogBuilder {
     facility: Option<syslog::Facility>,
     level: Level,
     logkind: Option<SyslogKind>,
+    format: Option<Box<dyn SyslogFormat>>,
+    process: Option<String>,
 }
 impl Default for SyslogBuilder {
     fn default() -> Self {
@@ -250,6 +547,8 @@ fn default() -> Self {
             facility: None,
             level: Level::Trace,
             logkind: None,
+            format: None,
+            process: None,
         }
     }
 }
@@ -275,6 +574,20 @@ pub fn level(self, lvl: slog::Level) -> Self {
         s
     }
 
+    /// Format of the messages. `Format3164` by default, `Format5424` switches to RFC 5424
+    pub fn format<F: SyslogFormat + 'static>(self, format: F) -> Self {
+        let mut s = self;
+        s.format = Some(Box::new(format));
+        s
+    }
+
+    /// Process name (RFC 3164 TAG, RFC 5424 APP-NAME). Name of the executable by default
+    pub fn process<S: AsRef<str>>(self, process: S) -> Self {
+        let mut s = self;
+        s.process = Some(process.as_ref().to_string());
+        s
+    }
+
     /// Remote UDP syslogging
     pub fn udp<S: AsRef<str>>(self, local: SocketAddr, host: SocketAddr, hostname: S) -> Self {
         let mut s = self;
@@ -323,31 +636,47 @@ pub fn start(self) -> io::Result<Streamer3164> {
                 ));
             }
         };
+        let format = self
+            .format
+            .unwrap_or_else(|| Box::new(Format3164::new()));
         let log = match logkind {
             SyslogKind::Unix { path } => {
-                let format = syslog_format3164(facility, None);
-                syslog::unix_custom(format, path).map_err(handle_syslog_error)?
+                let formatter = SyslogFormatter {
+                    header: syslog_header(&*format, facility, None, self.process),
+                    framing: false,
+                };
+                syslog::unix_custom(formatter, path).map_err(handle_syslog_error)?
             }
             SyslogKind::Udp {
                 local,
                 host,
                 hostname,
             } => {
-                let format = syslog_format3164(facility, Some(hostname));
-                syslog::udp(format, local, host).map_err(handle_syslog_error)?
+                let formatter = SyslogFormatter {
+                    header: syslog_header(&*format, facility, Some(hostname), self.process),
+                    framing: false,
+                };
+                syslog::udp(formatter, local, host).map_err(handle_syslog_error)?
             }
             SyslogKind::Tcp { server, hostname } => {
-                let format = syslog_format3164(facility, Some(hostname));
-                syslog::tcp(format, server).map_err(handle_syslog_error)?
+                let formatter = SyslogFormatter {
+                    header: syslog_header(&*format, facility, Some(hostname), self.process),
+                    framing: true,
+                };
+                syslog::tcp(formatter, server).map_err(handle_syslog_error)?
             }
         };
-        Ok(Streamer3164::new_with_level(Box::new(log), self.level))
+        Ok(Streamer3164::new_with_format(
+            Box::new(log),
+            self.level,
+            format,
+        ))
     }
 }
 
 /// `Streamer` to Unix syslog using RFC 3164 format
 pub fn unix_3164_with_level(facility: syslog::Facility, level: Level) -> io::Result<Streamer3164> {
-    let format = syslog_format3164(facility, None);
+    let format = SyslogFormatter::from(syslog_format3164(facility, None, None));
     syslog::unix(format)
         .map(Box::new)
         .map(|logger| Streamer3164::new_with_level(logger, level))
@@ -356,9 +685,90 @@ pub fn unix_3164_with_level(facility: syslog::Facility, level: Level) -> io::Res
 
 /// `Streamer` to Unix syslog using RFC 3164 format
 pub fn unix_3164(facility: syslog::Facility) -> io::Result<Streamer3164> {
-    let format = syslog_format3164(facility, None);
+    let format = SyslogFormatter::from(syslog_format3164(facility, None, None));
     syslog::unix(format)
         .map(Box::new)
         .map(Streamer3164::new)
         .map_err(handle_syslog_error)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{b, o, record};
+    use syslog::LogFormat;
+
+    fn format5424(format: &Format5424, record: &Record) -> String {
+        let mut buf = Vec::new();
+        format.format(&mut buf, record, &o!().into()).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn header5424_fields() {
+        let header = Header5424 {
+            facility: syslog::Facility::LOG_USER,
+            hostname: Some("my host".to_string()),
+            app_name: "lxc-tool".to_string(),
+            pid: 42,
+            msgid: None,
+        };
+        let mut buf = Vec::new();
+        header
+            .format(
+                &mut buf,
+                syslog::Severity::LOG_INFO,
+                "[sd@1 a=\"b\"] message",
+            )
+            .unwrap();
+        let line = String::from_utf8(buf).unwrap();
+
+        let fields: Vec<&str> = line.splitn(8, ' ').collect();
+        assert_eq!(fields[0], "<14>1");
+        assert!(fields[1].ends_with('Z'), "{}", fields[1]);
+        assert_eq!(fields[2], "my_host");
+        assert_eq!(fields[3], "lxc-tool");
+        assert_eq!(fields[4], "42");
+        assert_eq!(fields[5], "-");
+        assert_eq!(fields[6], "[sd@1");
+        assert_eq!(fields[7], "a=\"b\"] message");
+    }
+
+    #[test]
+    fn format5424_escapes_param_values() {
+        let format = Format5424::new().sd_id("test@1");
+
+        assert_eq!(
+            format5424(
+                &format,
+                &record!(
+                    Level::Info,
+                    "",
+                    &format_args!("message"),
+                    b!("path" => "C:\\dir \"x\" [y]")
+                )
+            ),
+            "[test@1 path=\"C:\\\\dir \\\"x\\\" [y\\]\"] message"
+        );
+    }
+
+    #[test]
+    fn format5424_without_params_is_nilvalue() {
+        assert_eq!(
+            format5424(
+                &Format5424::new(),
+                &record!(Level::Info, "", &format_args!("message"), b!())
+            ),
+            "- message"
+        );
+    }
+
+    #[test]
+    fn sd_name_sanitises_invalid_characters() {
+        assert_eq!(sd_name("image_name"), "image_name");
+        assert_eq!(sd_name("a=b c]d\"e\u{e9}"), "a_b_c_d_e_");
+        assert_eq!(sd_name(""), "_");
+        assert_eq!(sd_name(&"x".repeat(40)), "x".repeat(32));
+        assert_eq!(Format5424::new().sd_id("my id=1").sd_id, "my_id_1");
+    }
+}
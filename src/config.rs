@@ -1,6 +1,8 @@
-use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use crate::daemon::CronSchedule;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf};
 use url::Url;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -26,12 +28,233 @@ fn from(level: LogLevel) -> Self {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotate {
+    Hourly,
+    Daily,
+}
+
+fn default_log_file_keep() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "LogFileSpec")]
+pub struct LogFile {
+    pub path: PathBuf,
+    // Rotate when the file would grow beyond this many bytes
+    pub max_size: Option<u64>,
+    // Rotate at the start of every hour or day (UTC)
+    pub rotate: Option<LogRotate>,
+    // Number of rotated files retained as <path>.1 .. <path>.<keep>
+    pub keep: usize,
+}
+
+// A log file is either a plain path or a path with rotation settings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LogFileSpec {
+    Path(PathBuf),
+    Rotated {
+        path: PathBuf,
+        #[serde(default)]
+        max_size: Option<u64>,
+        #[serde(default)]
+        rotate: Option<LogRotate>,
+        #[serde(default = "default_log_file_keep")]
+        keep: usize,
+    },
+}
+
+impl From<LogFileSpec> for LogFile {
+    fn from(spec: LogFileSpec) -> Self {
+        match spec {
+            LogFileSpec::Path(path) => LogFile {
+                path,
+                max_size: None,
+                rotate: None,
+                keep: default_log_file_keep(),
+            },
+            LogFileSpec::Rotated {
+                path,
+                max_size,
+                rotate,
+                keep,
+            } => LogFile {
+                path,
+                max_size,
+                rotate,
+                keep,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    Syslog,
+    Stdout,
+    File(LogFile),
+}
+
+fn default_log_output() -> Vec<LogOutput> {
+    vec![LogOutput::Syslog]
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+    #[default]
+    Rfc3164,
+    // Key-values are sent as structured data instead of in the message text
+    Rfc5424,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    // Path of the local syslog socket
+    Unix(PathBuf),
+    Udp {
+        #[serde(default = "default_udp_local")]
+        local: SocketAddr,
+        server: SocketAddr,
+        // Sent in the message header. Local hostname if not set
+        #[serde(default)]
+        hostname: Option<String>,
+    },
+    Tcp {
+        server: SocketAddr,
+        // Sent in the message header. Local hostname if not set
+        #[serde(default)]
+        hostname: Option<String>,
+    },
+}
+
+impl Default for SyslogTransport {
+    fn default() -> Self {
+        SyslogTransport::Unix(PathBuf::from("/dev/log"))
+    }
+}
+
+fn default_udp_local() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+fn default_facility() -> String {
+    "user".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Logging {
+    #[serde(default)]
+    pub protocol: SyslogProtocol,
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub transport: SyslogTransport,
+    // Syslog facility name like user, daemon or local0
+    #[serde(default = "default_facility")]
+    pub facility: String,
+    // Process name sent with every message. Name of the executable if not set
+    #[serde(default)]
+    pub process: Option<String>,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            protocol: SyslogProtocol::default(),
+            transport: SyslogTransport::default(),
+            facility: default_facility(),
+            process: None,
+        }
+    }
+}
+
+impl Logging {
+    pub fn facility(&self) -> Result<slog_syslog::Facility> {
+        self.facility
+            .parse()
+            .map_err(|_| anyhow!("Invalid syslog facility '{}'", self.facility))
+    }
+}
+
 pub type Timeout = u64;
 
+// Secrets are read when the HTTP client is created instead of being stored in the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Secret {
+    // File containing the secret. Trailing newlines are ignored
+    File(PathBuf),
+    // Name of the environment variable containing the secret
+    Env(String),
+}
+
+impl Secret {
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Secret::File(path) => Ok(std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read secret file {:?}", path))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()),
+            Secret::Env(name) => std::env::var(name)
+                .with_context(|| format!("Failed to read secret environment variable {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    Basic { username: String, password: Secret },
+    Bearer(Secret),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HttpHeaderValue {
+    Value(String),
+    Secret(Secret),
+}
+
+// Upstream as http(s):// or file:// URL or absolute path of a local directory
+fn deserialize_origin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Url, D::Error> {
+    let origin = String::deserialize(deserializer)?;
+    let mut url = if origin.starts_with('/') {
+        Url::from_file_path(&origin)
+            .map_err(|_| D::Error::custom(format!("Invalid origin path '{}'", origin)))?
+    } else {
+        Url::parse(&origin).map_err(D::Error::custom)?
+    };
+
+    // Image paths are joined to the origin, so a local directory needs a trailing slash
+    if url.scheme() == "file" && !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+
+    Ok(url)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TargetUrl {
+    #[serde(deserialize_with = "deserialize_origin")]
     pub origin: Url,
     pub index_uri: String,
+    // Sent with index and image requests
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub auth: Option<Auth>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, HttpHeaderValue>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -109,12 +332,261 @@ pub struct Repodata {
     pub patcher_timeout: Timeout,
     // Directory for temporary files. Must be on the same FS as host_root_dir
     pub temporary_download_directory: PathBuf,
+    // Images relative to host_root_dir that are never removed by cleanup, prune or remove
+    #[serde(default)]
+    pub pinned_images: Vec<PathBuf>,
+    // Download rate limits, optionally different per time of day
+    #[serde(default)]
+    pub bandwidth: Bandwidth,
+    // Free space checked before every image is downloaded
+    #[serde(default)]
+    pub disk_space: DiskSpace,
+    // Hardlink identical files of new builds through the content-hash store in host_root_dir
+    #[serde(default)]
+    pub dedup: bool,
+    // Files listed in SHA256SUMS below this directory are used instead of downloading them
+    #[serde(default)]
+    pub seed_directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskSpace {
+    // Bytes that must stay free on the filesystem of temporary_download_directory
+    #[serde(default)]
+    pub reserve: u64,
+    // Remove the oldest unpinned builds, keeping the newest build of every image, when an image does not fit
+    #[serde(default)]
+    pub emergency_prune: bool,
+}
+
+// Minutes since midnight UTC, written as HH:MM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u32);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let (hour, minute) = value
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid time of day '{}'. Expected HH:MM", value))?;
+        let hour: u32 = hour.parse()?;
+        let minute: u32 = minute.parse()?;
+
+        if hour > 24 || minute > 59 || (hour == 24 && minute != 0) {
+            bail!("Invalid time of day '{}'. Expected HH:MM", value);
+        }
+
+        Ok(TimeOfDay(hour * 60 + minute))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time_of_day: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", time_of_day.0 / 60, time_of_day.0 % 60)
+    }
+}
+
+impl TimeOfDay {
+    pub fn of_unix_time(secs: u64) -> Self {
+        TimeOfDay((secs % 86400 / 60) as u32)
+    }
+}
+
+// Bytes per second. Unlimited if not set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimit {
+    // Shared by all downloads of a run
+    #[serde(default)]
+    pub global: Option<u64>,
+    #[serde(default)]
+    pub per_download: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BandwidthWindow {
+    // Start and end in UTC. Windows ending before they start span midnight
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+    #[serde(flatten)]
+    pub limit: BandwidthLimit,
+}
+
+impl BandwidthWindow {
+    fn contains(&self, time_of_day: TimeOfDay) -> bool {
+        if self.from <= self.to {
+            self.from <= time_of_day && time_of_day < self.to
+        } else {
+            self.from <= time_of_day || time_of_day < self.to
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Bandwidth {
+    // Limits outside of all windows
+    #[serde(flatten)]
+    pub limit: BandwidthLimit,
+    // The first window containing the current time replaces the limits
+    #[serde(default)]
+    pub windows: Vec<BandwidthWindow>,
+}
+
+impl Bandwidth {
+    pub fn limit_at(&self, time_of_day: TimeOfDay) -> BandwidthLimit {
+        self.windows
+            .iter()
+            .find(|window| window.contains(time_of_day))
+            .map_or(self.limit, |window| window.limit)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    // Seconds between the end of a run and the start of the next one
+    Interval(Timeout),
+    // Cron expression evaluated in UTC
+    Cron(CronSchedule),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Daemon {
+    // When download_images runs in daemon mode
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub schedule: Schedule,
+    // Maximum random delay in seconds added to every scheduled run
+    #[serde(default)]
+    pub jitter: Timeout,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metrics {
+    // Address of the /metrics endpoint in daemon mode
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+    // node_exporter textfile written at the end of each download run
+    #[serde(default)]
+    pub textfile: Option<PathBuf>,
+}
+
+fn default_progress_log_percent() -> u64 {
+    10
+}
+
+fn default_progress_log_interval() -> Timeout {
+    30
+}
+
+fn default_connect_timeout() -> Timeout {
+    30
+}
+
+fn default_read_timeout() -> Timeout {
+    300
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Http {
+    // Seconds, 0 disables the timeout
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: Timeout,
+    // Seconds without receiving any data, 0 disables the timeout
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: Timeout,
+    // Proxy for all requests. HTTP_PROXY/HTTPS_PROXY/NO_PROXY are used if not set
+    #[serde(default)]
+    pub proxy: Option<Url>,
+    // Hosts and domains reached without the proxy
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    // PEM file with CA certificates trusted in addition to the system ones
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+    // PEM client certificate and PKCS#8 key for TLS client authentication
+    #[serde(default)]
+    pub client_certificate: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    // Accept invalid server certificates. For testing only
+    #[serde(default)]
+    pub insecure: bool,
+    // lxc-tool/<version> if not set
+    #[serde(default)]
+    pub user_agent: Option<String>,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            connect_timeout: default_connect_timeout(),
+            read_timeout: default_read_timeout(),
+            proxy: None,
+            no_proxy: vec![],
+            ca_bundle: None,
+            client_certificate: None,
+            client_key: None,
+            insecure: false,
+            user_agent: None,
+        }
+    }
+}
+
+// Periodic log lines replacing progress bars when stderr is not a terminal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Progress {
+    #[serde(default = "default_progress_log_percent")]
+    pub log_percent: u64,
+    // Seconds
+    #[serde(default = "default_progress_log_interval")]
+    pub log_interval: Timeout,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            log_percent: default_progress_log_percent(),
+            log_interval: default_progress_log_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Systemd {
+    // Send READY=1, STATUS= and watchdog notifications. Auto-detected from NOTIFY_SOCKET if not set
+    #[serde(default)]
+    pub notify: Option<bool>,
+    // Log to journald with structured fields. Auto-detected from JOURNAL_STREAM if not set
+    #[serde(default)]
+    pub journald: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub log_level: LogLevel,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    // Log destinations. Ignored when RUST_LOG is set or journald logging is enabled
+    #[serde(
+        default = "default_log_output",
+        with = "serde_yaml::with::singleton_map_recursive"
+    )]
+    pub log_output: Vec<LogOutput>,
+    // Syslog output settings
+    #[serde(default)]
+    pub logging: Logging,
     pub repodata: Repodata,
+    #[serde(default)]
+    pub daemon: Option<Daemon>,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub systemd: Systemd,
+    #[serde(default)]
+    pub progress: Progress,
+    #[serde(default)]
+    pub http: Http,
 }
 
 impl Config {
@@ -152,8 +624,25 @@ fn validate_temporary_download_directory(&self) -> Result<()> {
         Ok(())
     }
 
+    fn validate_log_format(&self) -> Result<()> {
+        // RFC 5424 messages carry the key-values as structured data, there is no JSON variant
+        if matches!(self.logging.protocol, SyslogProtocol::Rfc5424)
+            && matches!(self.log_format, LogFormat::Json)
+            && self
+                .log_output
+                .iter()
+                .any(|log_output| matches!(log_output, LogOutput::Syslog))
+        {
+            anyhow::bail!("log_format json is not supported for syslog with protocol rfc5424");
+        }
+
+        Ok(())
+    }
+
     fn validate(&self) -> Result<()> {
         self.validate_temporary_download_directory()?;
+        self.validate_log_format()?;
+        self.logging.facility()?;
 
         Ok(())
     }
@@ -171,7 +660,7 @@ pub fn read(file: &str) -> Result<Self> {
 
 #[cfg(test)]
 mod tests {
-    use super::ImageFilter;
+    use super::{Bandwidth, BandwidthLimit, ImageFilter, TimeOfDay};
 
     use proptest::prelude::*;
 
@@ -197,4 +686,32 @@ fn iterator_field(dist: Option<String>, release: Option<String>, arch: Option<St
             assert_eq!(result,expected);
         }
     }
+
+    #[test]
+    fn bandwidth_windows() {
+        let bandwidth: Bandwidth = serde_yaml::from_str(
+            "per_download: 1000\nwindows:\n  - {from: '08:00', to: '20:00', global: 20}\n  - {from: '22:00', to: '02:00', global: 10}\n",
+        )
+        .unwrap();
+        let at = |time: &str| bandwidth.limit_at(TimeOfDay::try_from(time.to_string()).unwrap());
+
+        assert_eq!(
+            at("07:59"),
+            BandwidthLimit {
+                global: None,
+                per_download: Some(1000)
+            }
+        );
+        assert_eq!(
+            at("08:00"),
+            BandwidthLimit {
+                global: Some(20),
+                per_download: None
+            }
+        );
+        assert_eq!(at("20:00").global, None);
+        assert_eq!(at("23:30").global, Some(10));
+        assert_eq!(at("01:59").global, Some(10));
+        assert!(TimeOfDay::try_from("25:00".to_string()).is_err());
+    }
 }
@@ -0,0 +1,211 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
+
+const MINUTE: u64 = 60;
+// Upper bound of the next run search. Every valid expression matches within four years
+const SEARCH_LIMIT_MINUTES: u64 = 4 * 366 * 24 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CronField {
+    values: Vec<bool>,
+    // Field starts with `*` (like `*/2`). Used for the day of month and day of week combination rule
+    any: bool,
+}
+
+impl CronField {
+    fn of(input: &str, min: u32, max: u32) -> Result<Self> {
+        let mut values = vec![false; max as usize + 1];
+
+        for part in input.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (
+                    range,
+                    step.parse::<u32>()
+                        .with_context(|| format!("Invalid cron step '{}'", step))?,
+                ),
+                None => (part, 1),
+            };
+
+            if step == 0 {
+                bail!("Invalid cron step '0'");
+            }
+
+            let (start, end) = match range {
+                "*" => (min, max),
+                range => match range.split_once('-') {
+                    Some((start, end)) => (start.parse()?, end.parse()?),
+                    None => {
+                        let value = range
+                            .parse()
+                            .with_context(|| format!("Invalid cron value '{}'", range))?;
+                        (value, if part.contains('/') { max } else { value })
+                    }
+                },
+            };
+
+            if start < min || end > max || start > end {
+                bail!("Cron value '{}' is out of range {}-{}", part, min, max);
+            }
+
+            for value in (start..=end).step_by(step as usize) {
+                values[value as usize] = true;
+            }
+        }
+
+        Ok(Self {
+            values,
+            any: input.starts_with('*'),
+        })
+    }
+
+    fn contains(&self, value: u32) -> bool {
+        self.values.get(value as usize).copied().unwrap_or(false)
+    }
+}
+
+// Five field cron expression: minute, hour, day of month, month, day of week. Evaluated in UTC
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+    expression: String,
+    minute: CronField,
+    hour: CronField,
+    day_of_month: CronField,
+    month: CronField,
+    day_of_week: CronField,
+}
+
+impl CronSchedule {
+    pub fn of(expression: &str) -> Result<Self> {
+        match expression.split_whitespace().collect::<Vec<_>>().as_slice() {
+            &[minute, hour, day_of_month, month, day_of_week] => {
+                let mut day_of_week = CronField::of(day_of_week, 0, 7)?;
+                // Both 0 and 7 are Sunday
+                if day_of_week.contains(7) {
+                    day_of_week.values[0] = true;
+                }
+
+                Ok(Self {
+                    expression: expression.to_string(),
+                    minute: CronField::of(minute, 0, 59)?,
+                    hour: CronField::of(hour, 0, 23)?,
+                    day_of_month: CronField::of(day_of_month, 1, 31)?,
+                    month: CronField::of(month, 1, 12)?,
+                    day_of_week,
+                })
+            }
+            _ => bail!(
+                "Invalid cron expression '{}'. Expected 5 fields.",
+                expression
+            ),
+        }
+    }
+
+    fn matches(&self, time: Duration) -> bool {
+        let minutes = time.as_secs() / MINUTE;
+        let days = minutes / (24 * 60);
+        let (_, month, day) = civil_from_days(days);
+        // 1970-01-01 was Thursday
+        let day_of_week = ((days + 4) % 7) as u32;
+
+        let day_matches = match (self.day_of_month.any, self.day_of_week.any) {
+            (false, false) => {
+                self.day_of_month.contains(day) || self.day_of_week.contains(day_of_week)
+            }
+            _ => self.day_of_month.contains(day) && self.day_of_week.contains(day_of_week),
+        };
+
+        self.minute.contains((minutes % 60) as u32)
+            && self.hour.contains(((minutes / 60) % 24) as u32)
+            && self.month.contains(month)
+            && day_matches
+    }
+
+    // First matching time strictly after `now`, as a duration since the UNIX epoch
+    pub fn next_after(&self, now: Duration) -> Result<Duration> {
+        let start = now.as_secs() / MINUTE + 1;
+
+        (start..start + SEARCH_LIMIT_MINUTES)
+            .map(|minutes| Duration::from_secs(minutes * MINUTE))
+            .find(|time| self.matches(*time))
+            .ok_or_else(|| anyhow!("Cron expression '{}' never matches", self.expression))
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = anyhow::Error;
+
+    fn try_from(expression: String) -> Result<Self> {
+        Self::of(&expression)
+    }
+}
+
+impl From<CronSchedule> for String {
+    fn from(schedule: CronSchedule) -> Self {
+        schedule.expression
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+// Days since 1970-01-01 to (year, month, day) in the proleptic Gregorian calendar
+fn civil_from_days(days: u64) -> (u64, u32, u32) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CronSchedule;
+
+    use std::time::Duration;
+
+    // 2023-03-01 12:30:00 UTC, Wednesday
+    const NOW: Duration = Duration::from_secs(1677673800);
+
+    #[test]
+    fn next_after() {
+        let next = |expression| {
+            CronSchedule::of(expression)
+                .unwrap()
+                .next_after(NOW)
+                .unwrap()
+                .as_secs()
+        };
+
+        assert_eq!(next("* * * * *"), 1677673860);
+        assert_eq!(next("*/15 * * * *"), 1677674700);
+        assert_eq!(next("0 3 * * *"), 1677726000);
+        // Next Sunday, 2023-03-05 00:00
+        assert_eq!(next("0 0 * * 7"), 1677974400);
+        assert_eq!(next("0 0 1 1 *"), 1704067200);
+        // Both day fields apply when one starts with `*`: odd day that is a Monday, 2023-03-13
+        assert_eq!(next("0 0 */2 * 1"), 1678665600);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(CronSchedule::of("* * * *").is_err());
+        assert!(CronSchedule::of("60 * * * *").is_err());
+        assert!(CronSchedule::of("*/0 * * * *").is_err());
+        assert!(CronSchedule::of("0 0 31 2 *")
+            .unwrap()
+            .next_after(NOW)
+            .is_err());
+    }
+}
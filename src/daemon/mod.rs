@@ -0,0 +1,190 @@
+mod cron_schedule;
+
+use crate::{config, metrics, repodata, systemd};
+
+use anyhow::{anyhow, Result};
+use slog_scope::{error, info, warn};
+use std::{
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    time::sleep,
+};
+
+pub use cron_schedule::CronSchedule;
+
+struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+    hangup: Signal,
+}
+
+impl Signals {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+}
+
+async fn shutdown(terminate: &mut Signal, interrupt: &mut Signal) {
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+}
+
+fn jitter(max: config::Timeout) -> Duration {
+    if max == 0 {
+        return Duration::ZERO;
+    }
+
+    let seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64;
+
+    Duration::from_secs(seed % (max + 1))
+}
+
+fn next_run_delay(daemon: &config::Daemon) -> Result<Duration> {
+    let delay = match &daemon.schedule {
+        config::Schedule::Interval(interval) => Duration::from_secs(*interval),
+        config::Schedule::Cron(cron_schedule) => {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+            cron_schedule.next_after(now)? - now
+        }
+    };
+
+    Ok(delay + jitter(daemon.jitter))
+}
+
+fn reload_config(config_path: &str, config: &mut config::Config) {
+    match config::Config::read(config_path) {
+        Ok(new_config) if new_config.daemon.is_some() => {
+            systemd::notify("RELOADING=1");
+            *config = new_config;
+            systemd::notify_ready();
+            info!("Reload config file {:?} done.", config_path);
+        }
+        Ok(_) => error!(
+            "Reload config file {:?} failed. Section 'daemon' is missing.",
+            config_path
+        ),
+        Err(err) => error!("Reload config file failed with error: {:#}", err),
+    }
+}
+
+// Returns false when a shutdown signal was received
+async fn run_download_images(
+    config_path: &str,
+    config: &mut config::Config,
+    signals: &mut Signals,
+) -> Result<bool> {
+    let lock = match repodata::try_lock(config)? {
+        Some(lock) => lock,
+        None => {
+            warn!("Download LXC images skipped. Another run is in progress.");
+            return Ok(true);
+        }
+    };
+
+    info!("Download LXC images started.");
+
+    let download_images = repodata::download_images_with_lock(config.clone(), &lock);
+    tokio::pin!(download_images);
+
+    let mut reload_requested = false;
+    let mut shutdown_requested = false;
+
+    let result = loop {
+        tokio::select! {
+            result = &mut download_images => break result,
+            _ = signals.hangup.recv() => {
+                info!("Config reload will be done after the current run.");
+                reload_requested = true;
+            }
+            _ = shutdown(&mut signals.terminate, &mut signals.interrupt) => {
+                if shutdown_requested {
+                    // Dropping the download removes its temporary directory. An image still being
+                    // patched in the background is rolled back before it would be published
+                    repodata::request_abort();
+                    warn!("Download LXC images aborted. The current image is rolled back.");
+                    return Ok(false);
+                }
+
+                info!("Shutdown requested. Finishing the current image. Send the signal again to abort.");
+                shutdown_requested = true;
+                repodata::request_shutdown();
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => info!("Download LXC images done."),
+        Err(err) => error!("Download LXC images failed with error: {:#}", err),
+    }
+
+    if reload_requested {
+        reload_config(config_path, config);
+    }
+
+    Ok(!shutdown_requested)
+}
+
+pub async fn run(config_path: &str, mut config: config::Config) -> Result<()> {
+    let mut signals = Signals::new()?;
+
+    // Replaced after every run and reload, so the metrics follow the reloaded config
+    let metrics_config = Arc::new(RwLock::new(config.clone()));
+
+    if let Some(listen) = config.metrics.listen {
+        let metrics_config = metrics_config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(listen, metrics_config).await {
+                error!("Serve metrics failed with error: {:#}", err);
+            }
+        });
+    }
+
+    systemd::notify_ready();
+
+    loop {
+        *metrics_config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = config.clone();
+
+        let daemon = config.daemon.as_ref().ok_or_else(|| {
+            anyhow!("Daemon mode failed. Section 'daemon' is missing in config file.")
+        })?;
+        let delay = next_run_delay(daemon)?;
+
+        info!(
+            "Next download of LXC images in {} seconds.",
+            delay.as_secs()
+        );
+        systemd::notify_status(&format!(
+            "Next download of LXC images in {} seconds",
+            delay.as_secs()
+        ));
+
+        tokio::select! {
+            _ = sleep(delay) => {
+                if !run_download_images(config_path, &mut config, &mut signals).await? {
+                    break;
+                }
+            }
+            _ = signals.hangup.recv() => reload_config(config_path, &mut config),
+            _ = shutdown(&mut signals.terminate, &mut signals.interrupt) => break,
+        }
+    }
+
+    systemd::notify_stopping();
+    info!("Daemon stopped.");
+
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use crate::config;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, LOCATION},
+    redirect, Certificate, Client, Identity, Method, NoProxy, Proxy, RequestBuilder, Response,
+    StatusCode,
+};
+use std::{fs, future::Future, time::Duration};
+use tokio::time::timeout;
+use url::Url;
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+const MAX_REDIRECTS: usize = 10;
+
+// Client shared by all requests of a run to reuse connections
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    read_timeout: Option<Duration>,
+    // Credentials and custom headers, sent only to the origin of the upstream server
+    headers: HeaderMap,
+    origin: Url,
+}
+
+fn read_certificates(path: &std::path::Path) -> Result<Vec<Certificate>> {
+    let bundle = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read CA bundle. Path: {:?}", path))?;
+
+    bundle
+        .split_inclusive(PEM_CERTIFICATE_END)
+        .filter(|pem| pem.contains(PEM_CERTIFICATE_END))
+        .map(|pem| {
+            Certificate::from_pem(pem.trim().as_bytes())
+                .with_context(|| format!("Failed to parse CA bundle. Path: {:?}", path))
+        })
+        .collect()
+}
+
+fn read_identity(certificate: &std::path::Path, key: &std::path::Path) -> Result<Identity> {
+    let certificate_pem = fs::read(certificate)
+        .with_context(|| format!("Failed to read client certificate. Path: {:?}", certificate))?;
+    let key_pem =
+        fs::read(key).with_context(|| format!("Failed to read client key. Path: {:?}", key))?;
+
+    Identity::from_pkcs8_pem(&certificate_pem, &key_pem)
+        .with_context(|| "Failed to load client certificate")
+}
+
+fn sensitive_header_value(value: &str) -> Result<HeaderValue> {
+    let mut header_value = HeaderValue::from_str(value)?;
+    header_value.set_sensitive(true);
+    Ok(header_value)
+}
+
+fn target_url_headers(target_url: &config::TargetUrl) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in &target_url.headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name '{}'", name))?;
+        let header_value = match value {
+            config::HttpHeaderValue::Value(value) => HeaderValue::from_str(value)?,
+            config::HttpHeaderValue::Secret(secret) => sensitive_header_value(&secret.read()?)?,
+        };
+        headers.insert(header_name, header_value);
+    }
+
+    match &target_url.auth {
+        Some(config::Auth::Basic { username, password }) => {
+            let credentials = BASE64.encode(format!("{}:{}", username, password.read()?));
+            headers.insert(
+                AUTHORIZATION,
+                sensitive_header_value(&format!("Basic {}", credentials))?,
+            );
+        }
+        Some(config::Auth::Bearer(token)) => {
+            headers.insert(
+                AUTHORIZATION,
+                sensitive_header_value(&format!("Bearer {}", token.read()?))?,
+            );
+        }
+        None => {}
+    }
+
+    Ok(headers)
+}
+
+impl HttpClient {
+    pub fn new(config: &config::Config) -> Result<Self> {
+        let http = &config.http;
+        // Redirects are followed by send(), reqwest would keep custom headers on other hosts
+        let mut builder = Client::builder()
+            .redirect(redirect::Policy::none())
+            .user_agent(http.user_agent.clone().unwrap_or_else(|| {
+                format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            }));
+
+        if http.connect_timeout > 0 {
+            builder = builder.connect_timeout(Duration::from_secs(http.connect_timeout));
+        }
+
+        if let Some(proxy) = &http.proxy {
+            let proxy = Proxy::all(proxy.as_str())
+                .with_context(|| format!("Invalid proxy '{}'", proxy))?
+                .no_proxy(NoProxy::from_string(&http.no_proxy.join(",")));
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(ca_bundle) = &http.ca_bundle {
+            for certificate in read_certificates(ca_bundle)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&http.client_certificate, &http.client_key) {
+            (Some(certificate), Some(key)) => {
+                builder = builder.identity(read_identity(certificate, key)?);
+            }
+            (None, None) => {}
+            _ => bail!("Both client_certificate and client_key have to be set"),
+        }
+
+        if http.insecure {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            read_timeout: (http.read_timeout > 0).then(|| Duration::from_secs(http.read_timeout)),
+            headers: target_url_headers(&config.repodata.target_url)?,
+            origin: config.repodata.target_url.origin.clone(),
+        })
+    }
+
+    // Fails when nothing is received within read_timeout
+    async fn with_read_timeout<T>(&self, future: impl Future<Output = T>) -> Result<T> {
+        match self.read_timeout {
+            Some(read_timeout) => timeout(read_timeout, future).await.map_err(|_| {
+                anyhow!(
+                    "Read timeout of {} seconds exceeded",
+                    read_timeout.as_secs()
+                )
+            }),
+            None => Ok(future.await),
+        }
+    }
+
+    fn is_origin(&self, url: &Url) -> bool {
+        url.scheme() == self.origin.scheme()
+            && url.host_str() == self.origin.host_str()
+            && url.port_or_known_default() == self.origin.port_or_known_default()
+    }
+
+    async fn send_once(&self, request: RequestBuilder) -> Result<Response> {
+        Ok(self.with_read_timeout(request.send()).await??)
+    }
+
+    async fn send(&self, method: Method, url: &Url) -> Result<Response> {
+        let mut url = url.clone();
+
+        for _ in 0..=MAX_REDIRECTS {
+            let mut request = self.client.request(method.clone(), url.as_str());
+            if self.is_origin(&url) {
+                request = request.headers(self.headers.clone());
+            }
+
+            let response = self.send_once(request).await?;
+            let location = match response.status() {
+                StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT => response.headers().get(LOCATION),
+                _ => None,
+            };
+
+            match location {
+                Some(location) => url = url.join(location.to_str()?)?,
+                None => return Ok(response),
+            }
+        }
+
+        bail!("Too many redirects. URL: '{}'", url)
+    }
+
+    pub async fn get(&self, url: &Url) -> Result<Response> {
+        self.send(Method::GET, url).await
+    }
+
+    pub async fn head(&self, url: &Url) -> Result<Response> {
+        self.send(Method::HEAD, url).await
+    }
+
+    pub async fn next_chunk<S>(&self, stream: &mut S) -> Result<Option<Bytes>>
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
+    {
+        Ok(self.with_read_timeout(stream.next()).await?.transpose()?)
+    }
+
+    pub async fn text(&self, response: Response) -> Result<String> {
+        let mut stream = response.bytes_stream();
+        let mut body = vec![];
+
+        while let Some(chunk) = self.next_chunk(&mut stream).await? {
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(String::from_utf8(body)?)
+    }
+}
@@ -0,0 +1,281 @@
+use crate::config;
+
+use serde_json::{Map, Value};
+use slog::{Drain, Level, OwnedKVList, Record, KV};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    panic::{RefUnwindSafe, UnwindSafe},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+pub type BoxedDrain =
+    Box<dyn Drain<Ok = (), Err = slog::Never> + Send + Sync + UnwindSafe + RefUnwindSafe>;
+
+struct JsonSerializer<'a> {
+    fields: &'a mut Map<String, Value>,
+}
+
+impl slog::Serializer for JsonSerializer<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.fields
+            .insert(key.to_string(), Value::String(val.to_string()));
+        Ok(())
+    }
+
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.fields.insert(key.to_string(), Value::from(val));
+        Ok(())
+    }
+
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.fields.insert(key.to_string(), Value::from(val));
+        Ok(())
+    }
+
+    fn emit_usize(&mut self, key: slog::Key, val: usize) -> slog::Result {
+        self.fields.insert(key.to_string(), Value::from(val));
+        Ok(())
+    }
+
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.fields.insert(key.to_string(), Value::from(val));
+        Ok(())
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.fields.insert(key.to_string(), Value::from(val));
+        Ok(())
+    }
+}
+
+// One JSON object per record: timestamp, level, message and all slog key-values
+pub fn format_json(
+    io: &mut dyn Write,
+    record: &Record,
+    logger_values: &OwnedKVList,
+) -> io::Result<()> {
+    let mut fields = Map::new();
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .map_err(io::Error::other)?;
+
+    fields.insert("timestamp".to_string(), Value::String(timestamp));
+    fields.insert(
+        "level".to_string(),
+        Value::String(record.level().as_str().to_lowercase()),
+    );
+    fields.insert(
+        "message".to_string(),
+        Value::String(record.msg().to_string()),
+    );
+
+    let mut serializer = JsonSerializer {
+        fields: &mut fields,
+    };
+    logger_values.serialize(record, &mut serializer)?;
+    record.kv().serialize(record, &mut serializer)?;
+
+    serde_json::to_writer(io, &fields)?;
+    Ok(())
+}
+
+pub struct JsonSyslogFormat;
+
+impl slog_syslog::SyslogFormat for JsonSyslogFormat {
+    fn format(
+        &self,
+        io: &mut dyn Write,
+        record: &Record,
+        logger_kv: &OwnedKVList,
+    ) -> io::Result<()> {
+        format_json(io, record, logger_kv)
+    }
+}
+
+// Drain writing JSON lines to stdout or a file
+pub struct JsonDrain<W: Write> {
+    io: Mutex<W>,
+    level: Level,
+}
+
+impl<W: Write> JsonDrain<W> {
+    pub fn new(io: W, level: Level) -> Self {
+        Self {
+            io: Mutex::new(io),
+            level,
+        }
+    }
+}
+
+impl<W: Write> Drain for JsonDrain<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, logger_values: &OwnedKVList) -> io::Result<()> {
+        if !record.level().is_at_least(self.level) {
+            return Ok(());
+        }
+
+        let mut buf = Vec::with_capacity(256);
+        format_json(&mut buf, record, logger_values)?;
+        buf.push(b'\n');
+
+        let mut io = self
+            .io
+            .lock()
+            .map_err(|_| io::Error::other("locking error"))?;
+        io.write_all(&buf)?;
+        io.flush()
+    }
+}
+
+// Drain duplicating records to every configured output
+pub struct Drains(pub Vec<BoxedDrain>);
+
+impl Drain for Drains {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, logger_values: &OwnedKVList) -> Result<(), slog::Never> {
+        for drain in &self.0 {
+            drain.log(record, logger_values)?;
+        }
+        Ok(())
+    }
+}
+
+// Log file rotated by size or at hour/day boundaries, keeping `keep` old files as <path>.N
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    rotate: Option<config::LogRotate>,
+    keep: usize,
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+fn rotation_period(rotate: Option<config::LogRotate>, time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    match rotate {
+        Some(config::LogRotate::Hourly) => secs / 3600,
+        Some(config::LogRotate::Daily) => secs / 86400,
+        None => 0,
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated_path = path.as_os_str().to_owned();
+    rotated_path.push(format!(".{}", index));
+    PathBuf::from(rotated_path)
+}
+
+impl RotatingFile {
+    pub fn open(log_file: &config::LogFile) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file.path)?;
+        let metadata = file.metadata()?;
+
+        Ok(Self {
+            path: log_file.path.clone(),
+            max_size: log_file.max_size,
+            rotate: log_file.rotate,
+            keep: log_file.keep,
+            file,
+            size: metadata.len(),
+            period: rotation_period(log_file.rotate, metadata.modified()?),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                match fs::rename(
+                    rotated_path(&self.path, index),
+                    rotated_path(&self.path, index + 1),
+                ) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = rotation_period(self.rotate, SystemTime::now());
+        let oversized = self
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + buf.len() as u64 > max_size);
+
+        if oversized || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RotatingFile;
+    use crate::config::LogFile;
+    use std::{fs, io::Write};
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lxc-tool.log");
+        let mut file = RotatingFile::open(&LogFile {
+            path: path.clone(),
+            max_size: Some(10),
+            rotate: None,
+            keep: 2,
+        })
+        .unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(path.with_extension("log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(path.with_extension("log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!path.with_extension("log.3").exists());
+    }
+}
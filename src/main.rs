@@ -1,10 +1,23 @@
 mod config;
+mod daemon;
+mod http;
+mod logging;
+mod metrics;
+mod progress;
 mod repodata;
+mod server;
+mod systemd;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use slog::{o, Drain};
-use slog_scope::{error, info};
+use slog_scope::{error, info, warn};
+use std::{
+    net::SocketAddr,
+    panic::UnwindSafe,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
 const CONFIG_DEFAULT_PATH: &str = "/etc/lxc-tool.yaml";
 
@@ -22,12 +35,331 @@ async fn run(config: config::Config) -> Result<()> {
     }
 }
 
+struct CmdVerifyImages;
+
+impl CmdVerifyImages {
+    async fn run(config: config::Config, repair: bool) -> Result<()> {
+        info!("Verify LXC images started.");
+
+        repodata::verify_images(config, repair).await?;
+
+        info!("Verify LXC images done.");
+
+        Ok(())
+    }
+}
+
+struct CmdListRemoteImages;
+
+impl CmdListRemoteImages {
+    async fn run(
+        config: config::Config,
+        image_filter: Option<config::ImageFilter>,
+        output_format: repodata::OutputFormat,
+    ) -> Result<()> {
+        repodata::list_remote_images(config, image_filter, output_format).await
+    }
+}
+
+struct CmdListLocalImages;
+
+impl CmdListLocalImages {
+    async fn run(
+        config: config::Config,
+        image_filter: Option<config::ImageFilter>,
+        output_format: repodata::OutputFormat,
+    ) -> Result<()> {
+        repodata::list_local_images(config, image_filter, output_format).await
+    }
+}
+
+struct CmdDiffImages;
+
+impl CmdDiffImages {
+    async fn run(
+        config: config::Config,
+        checksum: bool,
+        output_format: repodata::OutputFormat,
+    ) -> Result<()> {
+        repodata::diff_images(config, checksum, output_format).await
+    }
+}
+
+struct CmdExportImages;
+
+impl CmdExportImages {
+    async fn run(
+        config: config::Config,
+        image_filter: Option<config::ImageFilter>,
+        bundle_path: &Path,
+    ) -> Result<()> {
+        info!("Export LXC images started.");
+
+        repodata::export_images(config, image_filter, bundle_path).await?;
+
+        info!("Export LXC images done.");
+
+        Ok(())
+    }
+}
+
+struct CmdImportImages;
+
+impl CmdImportImages {
+    async fn run(config: config::Config, bundle_path: &Path) -> Result<()> {
+        info!("Import LXC images started.");
+
+        repodata::import_images(config, bundle_path).await?;
+
+        info!("Import LXC images done.");
+
+        Ok(())
+    }
+}
+
+struct CmdAddImage;
+
+impl CmdAddImage {
+    async fn run(config: config::Config, args: &AddImageArgs) -> Result<()> {
+        let lxc_image_metadata = repodata::LXCImageMetadata::of_parts(
+            &args.dist,
+            &args.release,
+            &args.arch,
+            &args.type_,
+            &args.name,
+        )?;
+
+        repodata::add_image(
+            config,
+            lxc_image_metadata,
+            &args.rootfs,
+            &args.meta,
+            args.patch,
+        )
+        .await
+    }
+}
+
+struct CmdRemoveImages;
+
+impl CmdRemoveImages {
+    async fn run(
+        config: config::Config,
+        image_spec: &str,
+        quarantine_dir: Option<&Path>,
+    ) -> Result<()> {
+        info!("Remove LXC images started.");
+
+        repodata::remove_images(config, image_spec, quarantine_dir).await?;
+
+        info!("Remove LXC images done.");
+
+        Ok(())
+    }
+}
+
+struct CmdReindexImages;
+
+impl CmdReindexImages {
+    async fn run(config: config::Config) -> Result<()> {
+        info!("Reindex LXC images started.");
+
+        repodata::reindex_images(config).await?;
+
+        info!("Reindex LXC images done.");
+
+        Ok(())
+    }
+}
+
+struct CmdPruneImages;
+
+impl CmdPruneImages {
+    async fn run(config: config::Config, dry_run: bool) -> Result<()> {
+        info!("Prune LXC images started.");
+
+        repodata::prune_images(config, dry_run).await?;
+
+        info!("Prune LXC images done.");
+
+        Ok(())
+    }
+}
+
+struct CmdDedupImages;
+
+impl CmdDedupImages {
+    async fn run(config: config::Config) -> Result<()> {
+        info!("Dedup LXC images started.");
+
+        repodata::dedup_images(config).await?;
+
+        info!("Dedup LXC images done.");
+
+        Ok(())
+    }
+}
+
+struct CmdServe;
+
+impl CmdServe {
+    async fn run(config: config::Config, listen: SocketAddr, listing: bool) -> Result<()> {
+        server::serve(&config.repodata.host_root_dir, listen, listing).await
+    }
+}
+
+struct CmdDaemon;
+
+impl CmdDaemon {
+    async fn run(config_path: &str, config: config::Config) -> Result<()> {
+        info!("Daemon started.");
+
+        daemon::run(config_path, config).await
+    }
+}
+
+#[derive(Args)]
+struct ImageFilterArgs {
+    /// Filter images by distribution
+    #[clap(long)]
+    dist: Option<String>,
+    /// Filter images by release
+    #[clap(long)]
+    release: Option<String>,
+    /// Filter images by architecture
+    #[clap(long)]
+    arch: Option<String>,
+    /// Filter images by type
+    #[clap(long = "type")]
+    type_: Option<String>,
+}
+
+impl ImageFilterArgs {
+    fn image_filter(&self) -> Option<config::ImageFilter> {
+        if self.dist.is_none()
+            && self.release.is_none()
+            && self.arch.is_none()
+            && self.type_.is_none()
+        {
+            return None;
+        }
+
+        Some(config::ImageFilter {
+            dist: self.dist.clone(),
+            release: self.release.clone(),
+            arch: self.arch.clone(),
+            type_: self.type_.clone(),
+            post_process: None,
+        })
+    }
+}
+
+#[derive(Args)]
+struct AddImageArgs {
+    /// Image distribution
+    #[clap(long)]
+    dist: String,
+    /// Image release
+    #[clap(long)]
+    release: String,
+    /// Image architecture
+    #[clap(long)]
+    arch: String,
+    /// Image type
+    #[clap(long = "type", default_value = "default")]
+    type_: String,
+    /// Build name in the YYYYMMDD_HH:MM format
+    #[clap(long)]
+    name: String,
+    /// Run post_process of the matching image filter on the rootfs
+    #[clap(long)]
+    patch: bool,
+    /// Path to rootfs.tar.xz
+    rootfs: PathBuf,
+    /// Path to meta.tar.xz
+    meta: PathBuf,
+}
+
 #[derive(Subcommand)]
 enum CommandLine {
     /// Dump parsed config file. Helps to find typos
     DumpConfig,
     /// Download LXC images
     DownloadImages,
+    /// Verify local LXC images against config and index
+    Verify {
+        /// Remove broken images and download them again
+        #[clap(long)]
+        repair: bool,
+    },
+    /// List LXC images available on the upstream server
+    ListRemote {
+        #[clap(flatten)]
+        image_filter: ImageFilterArgs,
+        /// Output format
+        #[clap(long, value_enum, default_value_t = repodata::OutputFormat::Table)]
+        format: repodata::OutputFormat,
+    },
+    /// List LXC images stored in host_root_dir
+    ListLocal {
+        #[clap(flatten)]
+        image_filter: ImageFilterArgs,
+        /// Output format
+        #[clap(long, value_enum, default_value_t = repodata::OutputFormat::Table)]
+        format: repodata::OutputFormat,
+    },
+    /// Compare filtered upstream LXC images with host_root_dir
+    Diff {
+        /// Compare file checksums with upstream SHA256SUMS
+        #[clap(long)]
+        checksum: bool,
+        /// Output format
+        #[clap(long, value_enum, default_value_t = repodata::OutputFormat::Table)]
+        format: repodata::OutputFormat,
+    },
+    /// Export local LXC images into a tar bundle with a checksum manifest
+    Export {
+        #[clap(flatten)]
+        image_filter: ImageFilterArgs,
+        /// Path of the bundle file to create
+        bundle: PathBuf,
+    },
+    /// Validate a tar bundle and merge its LXC images into host_root_dir
+    Import {
+        /// Path of the bundle file created by export
+        bundle: PathBuf,
+    },
+    /// Publish a locally built LXC image in host_root_dir and regenerate the index
+    AddImage(AddImageArgs),
+    /// Remove LXC images from host_root_dir and regenerate the index
+    Remove {
+        /// Builds to remove: <dist>/<release>/<arch>/<type>[/<name>]
+        image: String,
+        /// Move the builds into this directory instead of deleting them
+        #[clap(long)]
+        quarantine: Option<PathBuf>,
+    },
+    /// Regenerate the index files from host_root_dir without downloading
+    Reindex,
+    /// Remove builds beyond number_of_container_to_backup without downloading
+    Prune {
+        /// Only print the builds that would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Hardlink identical files of the stored LXC images
+    Dedup,
+    /// Serve host_root_dir over HTTP
+    Serve {
+        /// Address to listen on
+        #[clap(long, default_value = "0.0.0.0:8080")]
+        listen: SocketAddr,
+        /// Enable directory listing
+        #[clap(long)]
+        listing: bool,
+    },
+    /// Download LXC images on the schedule from the daemon config section
+    Daemon,
 }
 
 #[derive(Parser)]
@@ -36,20 +368,136 @@ struct Application {
     /// Path to configuration file
     #[clap(short, default_value = CONFIG_DEFAULT_PATH)]
     config_path: String,
+    /// Show download progress bars. `auto` logs progress periodically when stderr is not a terminal
+    #[clap(long, value_enum, default_value_t = progress::ProgressMode::Auto, global = true)]
+    progress: progress::ProgressMode,
     /// Subcommand
     #[clap(subcommand)]
     command: CommandLine,
 }
 
 impl Application {
-    fn init_syslog_logger(log_level: slog::Level) -> Result<slog_scope::GlobalLoggerGuard> {
-        let logger = slog_syslog::SyslogBuilder::new()
-            .facility(slog_syslog::Facility::LOG_USER)
-            .level(log_level)
-            .unix("/dev/log")
-            .start()?;
-
-        let logger = slog::Logger::root(logger.fuse(), o!());
+    fn syslog_drain(
+        log_level: slog::Level,
+        log_format: config::LogFormat,
+        logging: &config::Logging,
+    ) -> Result<logging::BoxedDrain> {
+        let builder = slog_syslog::SyslogBuilder::new()
+            .facility(logging.facility()?)
+            .level(log_level);
+        let builder = match &logging.process {
+            Some(process) => builder.process(process),
+            None => builder,
+        };
+        let builder = match &logging.transport {
+            config::SyslogTransport::Unix(path) => builder.unix(path),
+            config::SyslogTransport::Udp {
+                local,
+                server,
+                hostname,
+            } => builder.udp(*local, *server, Self::syslog_hostname(hostname)),
+            config::SyslogTransport::Tcp { server, hostname } => {
+                builder.tcp(*server, Self::syslog_hostname(hostname))
+            }
+        };
+        let builder = match (logging.protocol, log_format) {
+            (config::SyslogProtocol::Rfc5424, _) => builder.format(
+                slog_syslog::Format5424::new().sd_id(concat!(env!("CARGO_PKG_NAME"), "@32473")),
+            ),
+            (config::SyslogProtocol::Rfc3164, config::LogFormat::Text) => builder,
+            (config::SyslogProtocol::Rfc3164, config::LogFormat::Json) => {
+                builder.format(logging::JsonSyslogFormat)
+            }
+        };
+
+        let drain = builder
+            .start()
+            .with_context(|| format!("Failed to connect to syslog {:?}", logging.transport))?;
+        Ok(Box::new(drain.fuse()))
+    }
+
+    fn syslog_hostname(hostname: &Option<String>) -> String {
+        hostname.clone().unwrap_or_else(|| {
+            nix::unistd::gethostname()
+                .map(|hostname| hostname.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "localhost".to_string())
+        })
+    }
+
+    fn writer_drain<W: std::io::Write + Send + UnwindSafe + 'static>(
+        io: W,
+        log_level: slog::Level,
+        log_format: config::LogFormat,
+    ) -> logging::BoxedDrain {
+        match log_format {
+            config::LogFormat::Text => {
+                let decorator = slog_term::PlainSyncDecorator::new(io);
+                let drain = slog_term::FullFormat::new(decorator).build();
+                Box::new(drain.filter_level(log_level).ignore_res())
+            }
+            config::LogFormat::Json => {
+                Box::new(logging::JsonDrain::new(io, log_level).ignore_res())
+            }
+        }
+    }
+
+    fn init_output_logger(config: &config::Config) -> Result<slog_scope::GlobalLoggerGuard> {
+        let log_level = config.log_level.into();
+        let mut syslog_errors = vec![];
+        let drains = config
+            .log_output
+            .iter()
+            .map(|log_output| match log_output {
+                config::LogOutput::Syslog => {
+                    Ok(
+                        Self::syslog_drain(log_level, config.log_format, &config.logging)
+                            .unwrap_or_else(|err| {
+                                // Keep logging when the syslog daemon is unavailable
+                                syslog_errors.push(err);
+                                Self::writer_drain(std::io::stderr(), log_level, config.log_format)
+                            }),
+                    )
+                }
+                config::LogOutput::Stdout => Ok(Self::writer_drain(
+                    std::io::stdout(),
+                    log_level,
+                    config.log_format,
+                )),
+                config::LogOutput::File(log_file) => {
+                    let file = logging::RotatingFile::open(log_file)
+                        .with_context(|| format!("Failed to open log file {:?}", log_file.path))?;
+                    Ok(Self::writer_drain(file, log_level, config.log_format))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let logger = slog::Logger::root(logging::Drains(drains), o!());
+        let guard = slog_scope::set_global_logger(logger);
+
+        for err in syslog_errors {
+            warn!("Logging to stderr instead of syslog. {:#}", err);
+        }
+
+        Ok(guard)
+    }
+
+    fn init_stderr_logger(config: &config::Config) -> slog_scope::GlobalLoggerGuard {
+        let drain = Self::writer_drain(
+            std::io::stderr(),
+            config.log_level.into(),
+            config.log_format,
+        );
+        slog_scope::set_global_logger(slog::Logger::root(drain, o!()))
+    }
+
+    fn init_journald_logger(log_level: slog::Level) -> Result<slog_scope::GlobalLoggerGuard> {
+        let logger = systemd::JournaldDrain::new(env!("CARGO_PKG_NAME"), log_level)?;
+
+        // Stderr is the journal stream, so a record journald refused still shows up there
+        let logger = logger
+            .map_err(|err| eprintln!("Write log record to journald failed with error: {}", err))
+            .ignore_res();
+        let logger = slog::Logger::root(logger, o!());
         Ok(slog_scope::set_global_logger(logger))
     }
 
@@ -60,8 +508,14 @@ fn init_env_logger() -> Result<slog_scope::GlobalLoggerGuard> {
     fn init_logger(&self, config: &config::Config) -> Result<slog_scope::GlobalLoggerGuard> {
         if std::env::var("RUST_LOG").is_ok() {
             Self::init_env_logger()
+        } else if config
+            .systemd
+            .journald
+            .unwrap_or_else(systemd::is_journal_stream)
+        {
+            Self::init_journald_logger(config.log_level.into())
         } else {
-            Self::init_syslog_logger(config.log_level.into())
+            Self::init_output_logger(config)
         }
     }
 
@@ -74,19 +528,77 @@ async fn run_command(&self, config: config::Config) -> Result<()> {
                 Ok(())
             }
             CommandLine::DownloadImages => CmdDownloadImages::run(config).await,
+            CommandLine::Verify { repair } => CmdVerifyImages::run(config, *repair).await,
+            CommandLine::ListRemote {
+                image_filter,
+                format,
+            } => CmdListRemoteImages::run(config, image_filter.image_filter(), *format).await,
+            CommandLine::ListLocal {
+                image_filter,
+                format,
+            } => CmdListLocalImages::run(config, image_filter.image_filter(), *format).await,
+            CommandLine::Diff { checksum, format } => {
+                CmdDiffImages::run(config, *checksum, *format).await
+            }
+            CommandLine::Export {
+                image_filter,
+                bundle,
+            } => CmdExportImages::run(config, image_filter.image_filter(), bundle).await,
+            CommandLine::Import { bundle } => CmdImportImages::run(config, bundle).await,
+            CommandLine::AddImage(args) => CmdAddImage::run(config, args).await,
+            CommandLine::Remove { image, quarantine } => {
+                CmdRemoveImages::run(config, image, quarantine.as_deref()).await
+            }
+            CommandLine::Reindex => CmdReindexImages::run(config).await,
+            CommandLine::Prune { dry_run } => CmdPruneImages::run(config, *dry_run).await,
+            CommandLine::Dedup => CmdDedupImages::run(config).await,
+            CommandLine::Serve { listen, listing } => {
+                CmdServe::run(config, *listen, *listing).await
+            }
+            CommandLine::Daemon => CmdDaemon::run(&self.config_path, config).await,
         }
     }
 
-    pub async fn run(&self) {
+    pub async fn run(&self) -> ExitCode {
         let config = config::Config::read(&self.config_path).expect("Config");
-        let _logger_guard = self.init_logger(&config).expect("Logger");
+        let _logger_guard = match self.init_logger(&config) {
+            Ok(guard) => guard,
+            Err(err) => {
+                let guard = Self::init_stderr_logger(&config);
+                warn!(
+                    "Logging to stderr. Init of logger failed with error: {:#}",
+                    err
+                );
+                guard
+            }
+        };
+
+        progress::init(self.progress, &config.progress);
 
-        if let Err(err) = self.run_command(config).await {
-            error!("Failed with error: {:#}", err);
+        if config
+            .systemd
+            .notify
+            .unwrap_or_else(systemd::is_notify_socket)
+        {
+            if let Err(err) = systemd::init_notify() {
+                error!("Failed with error: {:#}", err);
+            }
+        }
+
+        match self.run_command(config).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) if err.is::<repodata::ImagesDiffer>() => {
+                warn!("{}", err);
+                ExitCode::from(2)
+            }
+            Err(err) => {
+                error!("Failed with error: {:#}", err);
+                ExitCode::FAILURE
+            }
         }
     }
 }
 #[tokio::main]
-async fn main() {
-    Application::parse().run().await;
+async fn main() -> ExitCode {
+    Application::parse().run().await
 }
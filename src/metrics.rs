@@ -0,0 +1,305 @@
+use crate::{config, repodata};
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::{self, HeaderValue},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use slog_scope::{error, info};
+use std::{
+    convert::Infallible,
+    fmt::Write as _,
+    fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+static IMAGES_DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+static IMAGES_SKIPPED: AtomicU64 = AtomicU64::new(0);
+static IMAGES_FAILED: AtomicU64 = AtomicU64::new(0);
+static PATCH_DURATION_MILLIS: AtomicU64 = AtomicU64::new(0);
+static PATCH_COUNT: AtomicU64 = AtomicU64::new(0);
+static LAST_SUCCESS_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+// Walking the whole mirror is slow, so scrapes report the stats of the last refresh
+static IMAGE_GROUPS: Mutex<Vec<repodata::ImageGroupStats>> = Mutex::new(Vec::new());
+
+pub fn add_downloaded_bytes(bytes: u64) {
+    DOWNLOADED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn image_downloaded() {
+    IMAGES_DOWNLOADED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn image_skipped() {
+    IMAGES_SKIPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn image_failed() {
+    IMAGES_FAILED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn observe_patch_duration(duration: Duration) {
+    PATCH_DURATION_MILLIS.fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    PATCH_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn set_last_success() {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    LAST_SUCCESS_TIMESTAMP.store(now.as_secs(), Ordering::Relaxed);
+}
+
+// Runs on the blocking pool, the runtime keeps serving and syncing meanwhile
+pub async fn refresh_image_groups(config: &config::Config) -> Result<()> {
+    let config = config.clone();
+    let image_groups =
+        tokio::task::spawn_blocking(move || repodata::image_group_stats(&config)).await??;
+    *IMAGE_GROUPS.lock().unwrap_or_else(PoisonError::into_inner) = image_groups;
+
+    Ok(())
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_metric(output: &mut String, name: &str, type_: &str, help: &str, value: u64) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, type_);
+    let _ = writeln!(output, "{} {}", name, value);
+}
+
+pub fn render(config: &config::Config) -> Result<String> {
+    let mut output = String::new();
+
+    write_metric(
+        &mut output,
+        "lxc_tool_downloaded_bytes_total",
+        "counter",
+        "Bytes of LXC image files downloaded.",
+        DOWNLOADED_BYTES.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut output,
+        "lxc_tool_images_downloaded_total",
+        "counter",
+        "LXC images downloaded and published.",
+        IMAGES_DOWNLOADED.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut output,
+        "lxc_tool_images_skipped_total",
+        "counter",
+        "LXC images skipped because they are already present.",
+        IMAGES_SKIPPED.load(Ordering::Relaxed),
+    );
+    write_metric(
+        &mut output,
+        "lxc_tool_images_failed_total",
+        "counter",
+        "LXC images failed to download or patch.",
+        IMAGES_FAILED.load(Ordering::Relaxed),
+    );
+
+    let _ = writeln!(
+        output,
+        "# HELP lxc_tool_patch_duration_seconds Duration of LXC image post processing."
+    );
+    let _ = writeln!(output, "# TYPE lxc_tool_patch_duration_seconds summary");
+    let _ = writeln!(
+        output,
+        "lxc_tool_patch_duration_seconds_sum {:.3}",
+        PATCH_DURATION_MILLIS.load(Ordering::Relaxed) as f64 / 1000.0
+    );
+    let _ = writeln!(
+        output,
+        "lxc_tool_patch_duration_seconds_count {}",
+        PATCH_COUNT.load(Ordering::Relaxed)
+    );
+
+    write_metric(
+        &mut output,
+        "lxc_tool_last_success_timestamp_seconds",
+        "gauge",
+        "UNIX time of the last successful download of LXC images.",
+        LAST_SUCCESS_TIMESTAMP.load(Ordering::Relaxed),
+    );
+
+    let index_path = config
+        .repodata
+        .host_root_dir
+        .join(&config.repodata.target_url.index_uri);
+    let (index_size, index_entries) = match fs::read_to_string(&index_path) {
+        Ok(index) => (index.len() as u64, index.lines().count() as u64),
+        Err(_) => (0, 0),
+    };
+    write_metric(
+        &mut output,
+        "lxc_tool_index_size_bytes",
+        "gauge",
+        "Size of the LXC images index file.",
+        index_size,
+    );
+    write_metric(
+        &mut output,
+        "lxc_tool_index_entries",
+        "gauge",
+        "Number of entries in the LXC images index file.",
+        index_entries,
+    );
+
+    let image_groups = IMAGE_GROUPS.lock().unwrap_or_else(PoisonError::into_inner);
+    let _ = writeln!(
+        output,
+        "# HELP lxc_tool_builds Number of LXC image builds stored locally."
+    );
+    let _ = writeln!(output, "# TYPE lxc_tool_builds gauge");
+    for image_group in image_groups.iter() {
+        let _ = writeln!(
+            output,
+            "lxc_tool_builds{{dist=\"{}\",release=\"{}\",arch=\"{}\",type=\"{}\"}} {}",
+            escape_label(&image_group.dist),
+            escape_label(&image_group.release),
+            escape_label(&image_group.arch),
+            escape_label(&image_group.type_),
+            image_group.builds
+        );
+    }
+    let _ = writeln!(
+        output,
+        "# HELP lxc_tool_builds_size_bytes Disk usage of LXC image builds stored locally."
+    );
+    let _ = writeln!(output, "# TYPE lxc_tool_builds_size_bytes gauge");
+    for image_group in image_groups.iter() {
+        let _ = writeln!(
+            output,
+            "lxc_tool_builds_size_bytes{{dist=\"{}\",release=\"{}\",arch=\"{}\",type=\"{}\"}} {}",
+            escape_label(&image_group.dist),
+            escape_label(&image_group.release),
+            escape_label(&image_group.arch),
+            escape_label(&image_group.type_),
+            image_group.size
+        );
+    }
+
+    Ok(output)
+}
+
+fn parse_last_success(metrics: &str) -> Option<u64> {
+    metrics.lines().find_map(|line| {
+        line.strip_prefix("lxc_tool_last_success_timestamp_seconds ")?
+            .trim()
+            .parse()
+            .ok()
+    })
+}
+
+// Written atomically to be picked up by the node_exporter textfile collector
+pub fn write_textfile(textfile: &Path, config: &config::Config) -> Result<()> {
+    // A run that failed before any success in this process keeps the time of the previous file
+    if let Some(last_success) = fs::read_to_string(textfile)
+        .ok()
+        .and_then(|metrics| parse_last_success(&metrics))
+    {
+        let _ = LAST_SUCCESS_TIMESTAMP.compare_exchange(
+            0,
+            last_success,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    let metrics = render(config)?;
+    let textfile_tmp = textfile.with_extension("prom.tmp");
+
+    fs::write(&textfile_tmp, metrics)
+        .with_context(|| format!("Failed to write metrics file. Path: {:?}", textfile_tmp))?;
+    fs::rename(&textfile_tmp, textfile)
+        .with_context(|| format!("Failed to write metrics file. Path: {:?}", textfile))?;
+
+    Ok(())
+}
+
+async fn handle(
+    config: Arc<RwLock<config::Config>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("Not Found\n"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    let config = config
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let response = match render(&config) {
+        Ok(metrics) => {
+            let mut response = Response::new(Body::from(metrics));
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE));
+            response
+        }
+        Err(err) => {
+            error!("Render metrics failed with error: {:#}", err);
+            let mut response = Response::new(Body::from("Internal Server Error\n"));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    };
+
+    Ok(response)
+}
+
+// The config is shared with the daemon, which replaces it on reload
+pub async fn serve(listen: SocketAddr, config: Arc<RwLock<config::Config>>) -> Result<()> {
+    info!("Serve metrics on '{}' started.", listen);
+
+    let initial_config = config
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    if let Err(err) = refresh_image_groups(&initial_config).await {
+        error!("Refresh LXC image stats failed with error: {:#}", err);
+    }
+
+    let make_service = make_service_fn(move |_| {
+        let config = config.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(config.clone(), request))) }
+    });
+
+    Server::try_bind(&listen)
+        .with_context(|| format!("Failed to listen on '{}'", listen))?
+        .serve(make_service)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_last_success;
+
+    #[test]
+    fn last_success_from_textfile() {
+        let metrics = "# TYPE lxc_tool_last_success_timestamp_seconds gauge\nlxc_tool_last_success_timestamp_seconds 1677673800\nlxc_tool_index_entries 2\n";
+        assert_eq!(parse_last_success(metrics), Some(1677673800));
+        assert_eq!(parse_last_success("lxc_tool_index_entries 2\n"), None);
+    }
+}
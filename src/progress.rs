@@ -0,0 +1,152 @@
+use crate::config;
+
+use console::Term;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use slog_scope::info;
+use std::{
+    io::IsTerminal,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    // Progress bars on a terminal, periodic log lines otherwise
+    Auto,
+    Always,
+    Never,
+}
+
+struct Progress {
+    // Progress bars of the files of an image are stacked in one view
+    multi_progress: Option<MultiProgress>,
+    log: bool,
+    log_percent: u64,
+    log_interval: Duration,
+}
+
+static PROGRESS: OnceLock<Progress> = OnceLock::new();
+
+impl Progress {
+    fn new(mode: ProgressMode, config: &config::Progress) -> Self {
+        let is_terminal = std::io::stderr().is_terminal();
+        let draw_target = match mode {
+            ProgressMode::Auto if is_terminal => Some(ProgressDrawTarget::stderr()),
+            // indicatif hides its stderr target when it is not a terminal
+            ProgressMode::Always => Some(ProgressDrawTarget::term_like(Box::new(Term::stderr()))),
+            ProgressMode::Auto | ProgressMode::Never => None,
+        };
+
+        Self {
+            multi_progress: draw_target.map(MultiProgress::with_draw_target),
+            log: mode == ProgressMode::Auto && !is_terminal,
+            log_percent: config.log_percent.max(1),
+            log_interval: Duration::from_secs(config.log_interval),
+        }
+    }
+}
+
+pub fn init(mode: ProgressMode, config: &config::Progress) {
+    let _ = PROGRESS.set(Progress::new(mode, config));
+}
+
+fn progress() -> &'static Progress {
+    PROGRESS.get_or_init(|| Progress::new(ProgressMode::Auto, &config::Progress::default()))
+}
+
+pub struct DownloadProgress {
+    progress_bar: Option<ProgressBar>,
+    url: Url,
+    // Unknown when the server sends no Content-Length
+    total_size: Option<u64>,
+    logged_percent: u64,
+    logged_at: Instant,
+}
+
+pub fn download(url: &Url, total_size: Option<u64>) -> DownloadProgress {
+    let progress_bar = progress().multi_progress.as_ref().map(|multi_progress| {
+        let progress_bar = match total_size {
+            Some(total_size) => {
+                let progress_bar = ProgressBar::new(total_size);
+                progress_bar.set_style(ProgressStyle::default_bar()
+                    .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/green}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+                    .expect("Progress bar template")
+                    .progress_chars("#>-"));
+                progress_bar
+            }
+            None => {
+                let progress_bar = ProgressBar::new_spinner();
+                progress_bar.set_style(
+                    ProgressStyle::default_spinner()
+                        .template(
+                            "{msg}\n{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec})",
+                        )
+                        .expect("Progress spinner template"),
+                );
+                progress_bar
+            }
+        };
+        let progress_bar = multi_progress.add(progress_bar);
+        progress_bar.set_message(url.to_string());
+        progress_bar
+    });
+
+    DownloadProgress {
+        progress_bar,
+        url: url.clone(),
+        total_size,
+        logged_percent: 0,
+        logged_at: Instant::now(),
+    }
+}
+
+impl DownloadProgress {
+    pub fn set_position(&mut self, position: u64) {
+        if let Some(progress_bar) = &self.progress_bar {
+            progress_bar.set_position(position);
+        }
+
+        let progress = progress();
+        if !progress.log {
+            return;
+        }
+
+        match self.total_size {
+            Some(total_size) if position < total_size => {
+                let percent = position * 100 / total_size;
+                if percent >= self.logged_percent + progress.log_percent
+                    || self.logged_at.elapsed() >= progress.log_interval
+                {
+                    info!(
+                        "Download LXC image file {}% done.", percent;
+                        "url" => %self.url,
+                        "bytes" => position,
+                        "percent" => percent
+                    );
+                    self.logged_percent = percent - percent % progress.log_percent;
+                    self.logged_at = Instant::now();
+                }
+            }
+            Some(_) => {}
+            None => {
+                if self.logged_at.elapsed() >= progress.log_interval {
+                    info!(
+                        "Download LXC image file {} bytes done.", position;
+                        "url" => %self.url,
+                        "bytes" => position
+                    );
+                    self.logged_at = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DownloadProgress {
+    fn drop(&mut self) {
+        if let Some(progress_bar) = &self.progress_bar {
+            progress_bar.finish_and_clear();
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use super::{
+    lxc_image_checksum::{sha256_file, CHECKSUM_FILE},
+    lxc_image_metadata::{path_component, LXCImageMetadata},
+    lxc_image_store::ImageStore,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use slog_scope::info;
+use std::{
+    collections::HashSet,
+    fs::{self, File, FileTimes, Permissions},
+    io::Read,
+    os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tempfile::Builder;
+use walkdir::WalkDir;
+
+// First entry of a bundle describing every image file it contains
+const MANIFEST_FILE: &str = "MANIFEST.json";
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct BundleManifest {
+    version: u32,
+    created: u64,
+    images: Vec<BundleImage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleImage {
+    dist: String,
+    release: String,
+    arch: String,
+    #[serde(rename = "type")]
+    type_: String,
+    name: String,
+    mtime: u64,
+    files: Vec<BundleFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleFile {
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+impl BundleImage {
+    fn path(&self) -> Result<PathBuf> {
+        Ok(LXCImageMetadata::of_parts(
+            &self.dist,
+            &self.release,
+            &self.arch,
+            &self.type_,
+            &self.name,
+        )?
+        .path)
+    }
+}
+
+fn bundle_image(image_metadata: &LXCImageMetadata, mtime: Duration) -> Result<BundleImage> {
+    let mut file_paths = fs::read_dir(&image_metadata.path)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    file_paths.sort();
+
+    let files = file_paths
+        .iter()
+        .filter(|file_path| file_path.is_file())
+        .map(|file_path| {
+            let name = file_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    anyhow!("Convert file name to string error. Path: {:?}", file_path)
+                })?;
+
+            Ok(BundleFile {
+                name: name.to_string(),
+                size: fs::metadata(file_path)?.len(),
+                sha256: sha256_file(file_path)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(BundleImage {
+        dist: image_metadata.dist.clone(),
+        release: image_metadata.release.clone(),
+        arch: image_metadata.arch.clone(),
+        type_: image_metadata.type_.clone(),
+        name: image_metadata.name.clone(),
+        mtime: mtime.as_secs(),
+        files,
+    })
+}
+
+pub fn export_bundle(
+    image_entries: &[(LXCImageMetadata, Duration)],
+    bundle_path: &Path,
+) -> Result<usize> {
+    let created = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let manifest = BundleManifest {
+        version: MANIFEST_VERSION,
+        created: created.as_secs(),
+        images: image_entries
+            .iter()
+            .map(|(image_metadata, mtime)| bundle_image(image_metadata, *mtime))
+            .collect::<Result<_>>()?,
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+
+    // Written next to the bundle and renamed so an interrupted export leaves no partial file
+    let bundle_dir = match bundle_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let bundle_tempfile = Builder::new()
+        .prefix(".bundle_")
+        .tempfile_in(bundle_dir)
+        .with_context(|| format!("Failed to create bundle file in {:?}", bundle_dir))?;
+
+    let mut archive = tar::Builder::new(bundle_tempfile);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created);
+    archive.append_data(&mut header, MANIFEST_FILE, manifest_json.as_slice())?;
+
+    for ((image_metadata, _), image) in image_entries.iter().zip(&manifest.images) {
+        let image_path = image.path()?;
+        for file in &image.files {
+            archive.append_path_with_name(
+                image_metadata.path.join(&file.name),
+                image_path.join(&file.name),
+            )?;
+        }
+    }
+
+    archive
+        .into_inner()?
+        .persist(bundle_path)
+        .with_context(|| format!("Failed to write bundle file. Path: {:?}", bundle_path))?;
+
+    Ok(manifest.images.len())
+}
+
+fn unpack_bundle(bundle_path: &Path, unpack_dir: &Path) -> Result<BundleManifest> {
+    let bundle_file = File::open(bundle_path)
+        .with_context(|| format!("Failed to open bundle file. Path: {:?}", bundle_path))?;
+    let mut archive = tar::Archive::new(bundle_file);
+    let mut manifest = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => continue,
+            tar::EntryType::Regular => {}
+            entry_type => bail!(
+                "Unsupported bundle entry type {:?}. Path: {:?}",
+                entry_type,
+                entry_path
+            ),
+        }
+
+        if entry_path == Path::new(MANIFEST_FILE) {
+            let mut manifest_json = String::new();
+            entry.read_to_string(&mut manifest_json)?;
+            manifest = Some(
+                serde_json::from_str::<BundleManifest>(&manifest_json)
+                    .with_context(|| "Failed to parse bundle manifest")?,
+            );
+        } else if !entry.unpack_in(unpack_dir)? {
+            bail!(
+                "Bundle entry is outside of the bundle. Path: {:?}",
+                entry_path
+            );
+        }
+    }
+
+    let manifest =
+        manifest.ok_or_else(|| anyhow!("Bundle manifest {} is missing", MANIFEST_FILE))?;
+    if manifest.version != MANIFEST_VERSION {
+        bail!(
+            "Unsupported bundle manifest version {}. Expected version {}",
+            manifest.version,
+            MANIFEST_VERSION
+        );
+    }
+
+    Ok(manifest)
+}
+
+fn validate_bundle(manifest: &BundleManifest, unpack_dir: &Path) -> Result<()> {
+    let mut expected_paths = HashSet::new();
+
+    for image in &manifest.images {
+        let image_path = image.path()?;
+        for file in &image.files {
+            path_component(&file.name).with_context(|| "Invalid file name in bundle manifest")?;
+
+            let file_path = unpack_dir.join(&image_path).join(&file.name);
+            let size = fs::metadata(&file_path)
+                .with_context(|| format!("Bundle file is missing. Path: {:?}", file_path))?
+                .len();
+
+            if size != file.size {
+                bail!(
+                    "Bundle file size mismatch. Expected {} bytes, found {} bytes. Path: {:?}",
+                    file.size,
+                    size,
+                    file_path
+                );
+            }
+            if sha256_file(&file_path)? != file.sha256.to_lowercase() {
+                bail!("Bundle file checksum mismatch. Path: {:?}", file_path);
+            }
+
+            expected_paths.insert(file_path);
+        }
+    }
+
+    for entry in WalkDir::new(unpack_dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() && !expected_paths.contains(entry.path()) {
+            bail!(
+                "Bundle file is not listed in the manifest. Path: {:?}",
+                entry.path().strip_prefix(unpack_dir)?
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the number of images added. Images already present in root_dir are kept as they are
+pub fn import_bundle(
+    bundle_path: &Path,
+    temp_dir: &Path,
+    root_dir: &Path,
+    dedup: bool,
+) -> Result<usize> {
+    let unpack_dir = Builder::new().prefix(".import_").tempdir_in(temp_dir)?;
+
+    let manifest = unpack_bundle(bundle_path, unpack_dir.path())?;
+    validate_bundle(&manifest, unpack_dir.path())?;
+
+    let mut number_of_images = 0;
+
+    for image in &manifest.images {
+        let image_path = image.path()?;
+        let image_dir_path = root_dir.join(&image_path);
+
+        if image_dir_path.exists() {
+            info!(
+                "Import LXC image skipped. Image is already present.";
+                "path" => %image_path.display()
+            );
+            continue;
+        }
+
+        let unpacked_image_path = unpack_dir.path().join(&image_path);
+        fs::create_dir_all(&unpacked_image_path)?;
+        for file in &image.files {
+            fs::set_permissions(
+                unpacked_image_path.join(&file.name),
+                Permissions::from_mode(0o644),
+            )?;
+        }
+        fs::set_permissions(&unpacked_image_path, Permissions::from_mode(0o755))?;
+
+        if let Some(parent_dir_path) = image_dir_path.parent() {
+            fs::create_dir_all(parent_dir_path)?;
+        }
+        fs::rename(&unpacked_image_path, &image_dir_path)?;
+
+        // Linked once in host_root_dir, temp_dir may be on another filesystem
+        if dedup {
+            let store = ImageStore::new(root_dir);
+            for file in image.files.iter().filter(|file| file.name != CHECKSUM_FILE) {
+                store.link_with_checksum(&image_dir_path.join(&file.name), &file.sha256)?;
+            }
+        }
+
+        // Cleanup orders builds by directory mtime, so keep the one from the exporting host
+        File::open(&image_dir_path)?.set_times(
+            FileTimes::new()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(image.mtime)),
+        )?;
+
+        number_of_images += 1;
+        info!(
+            "Import LXC image done.";
+            "dist" => &image.dist,
+            "release" => &image.release,
+            "arch" => &image.arch,
+            "type" => &image.type_,
+            "name" => &image.name
+        );
+    }
+
+    Ok(number_of_images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_bundle, import_bundle};
+    use crate::repodata::lxc_image_metadata_entries_create::create_image_metadata_entries;
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    #[test]
+    fn export_import_round_trip() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let image_dir = source_dir
+            .path()
+            .join("images/debian/bookworm/amd64/default/20230201_01:00");
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::write(image_dir.join("rootfs.tar.xz"), "rootfs").unwrap();
+        fs::write(image_dir.join("meta.tar.xz"), "meta").unwrap();
+
+        let bundle_path = source_dir.path().join("bundle.tar");
+        let image_entries = create_image_metadata_entries(source_dir.path()).unwrap();
+        assert_eq!(export_bundle(&image_entries, &bundle_path).unwrap(), 1);
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            import_bundle(&bundle_path, temp_dir.path(), target_dir.path(), false).unwrap(),
+            1
+        );
+        assert_eq!(
+            fs::read_to_string(
+                target_dir
+                    .path()
+                    .join("images/debian/bookworm/amd64/default/20230201_01:00/rootfs.tar.xz")
+            )
+            .unwrap(),
+            "rootfs"
+        );
+
+        // Already present images are skipped
+        assert_eq!(
+            import_bundle(&bundle_path, temp_dir.path(), target_dir.path(), false).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn import_rejects_modified_file() {
+        let source_dir = tempfile::tempdir().unwrap();
+        let image_dir = source_dir
+            .path()
+            .join("images/debian/bookworm/amd64/default/20230201_01:00");
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::write(image_dir.join("rootfs.tar.xz"), "rootfs").unwrap();
+
+        let bundle_path = source_dir.path().join("bundle.tar");
+        let image_entries = create_image_metadata_entries(source_dir.path()).unwrap();
+        export_bundle(&image_entries, &bundle_path).unwrap();
+
+        let mut bundle = fs::read(&bundle_path).unwrap();
+        let offset = bundle
+            .windows(6)
+            .rposition(|window| window == b"rootfs")
+            .unwrap();
+        bundle[offset] = b'R';
+        fs::write(&bundle_path, bundle).unwrap();
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert!(import_bundle(&bundle_path, temp_dir.path(), target_dir.path(), false).is_err());
+        assert!(!target_dir.path().join("images").exists());
+    }
+
+    #[test]
+    fn import_links_files_into_store() {
+        let source_dir = tempfile::tempdir().unwrap();
+        for name in ["20230201_01:00", "20230301_01:00"] {
+            let image_dir = source_dir
+                .path()
+                .join("images/debian/bookworm/amd64/default")
+                .join(name);
+            fs::create_dir_all(&image_dir).unwrap();
+            fs::write(image_dir.join("rootfs.tar.xz"), "rootfs").unwrap();
+        }
+
+        let bundle_path = source_dir.path().join("bundle.tar");
+        let image_entries = create_image_metadata_entries(source_dir.path()).unwrap();
+        export_bundle(&image_entries, &bundle_path).unwrap();
+
+        let target_dir = tempfile::tempdir().unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            import_bundle(&bundle_path, temp_dir.path(), target_dir.path(), true).unwrap(),
+            2
+        );
+
+        let image_group_dir = target_dir
+            .path()
+            .join("images/debian/bookworm/amd64/default");
+        let first = fs::metadata(image_group_dir.join("20230201_01:00/rootfs.tar.xz")).unwrap();
+        let second = fs::metadata(image_group_dir.join("20230301_01:00/rootfs.tar.xz")).unwrap();
+        assert_eq!(first.ino(), second.ino());
+        assert_eq!(first.nlink(), 3);
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+// Checksum file published next to the image files by the upstream image server
+pub const CHECKSUM_FILE: &str = "SHA256SUMS";
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open file for checksum. Path: {:?}", path))?;
+    let mut hasher = Sha256::new();
+
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read file for checksum. Path: {:?}", path))?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn parse_checksums(checksums: &str) -> HashMap<String, String> {
+    checksums
+        .lines()
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                &[checksum, file_name] => Some((
+                    file_name.trim_start_matches('*').to_string(),
+                    checksum.to_lowercase(),
+                )),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+pub fn read_checksums(image_dir: &Path) -> Result<Option<HashMap<String, String>>> {
+    let checksum_path = image_dir.join(CHECKSUM_FILE);
+
+    if !checksum_path.exists() {
+        return Ok(None);
+    }
+
+    let checksums = fs::read_to_string(&checksum_path)
+        .with_context(|| format!("Failed to read checksum file. Path: {:?}", checksum_path))?;
+
+    Ok(Some(parse_checksums(&checksums)))
+}
+
+// Same format as the upstream file, so a published build can be verified later
+pub fn write_checksums(image_dir: &Path, checksums: &[(String, String)]) -> Result<()> {
+    let checksum_path = image_dir.join(CHECKSUM_FILE);
+    let checksums: String = checksums
+        .iter()
+        .map(|(file_name, checksum)| format!("{}  {}\n", checksum, file_name))
+        .collect();
+
+    fs::write(&checksum_path, checksums)
+        .with_context(|| format!("Failed to write checksum file. Path: {:?}", checksum_path))
+}
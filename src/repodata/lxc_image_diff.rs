@@ -0,0 +1,172 @@
+use crate::{config::ImageFiles, http::HttpClient};
+
+use super::{
+    lxc_image_checksum::{parse_checksums, sha256_file, CHECKSUM_FILE},
+    lxc_image_list::TableRow,
+    lxc_image_local::{is_local, local_path, read_to_string},
+    lxc_image_metadata::LXCImageMetadata,
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::{header::CONTENT_LENGTH, StatusCode};
+use serde::Serialize;
+use std::{collections::HashMap, path::Path};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LXCImageDiffStatus {
+    // Build is published upstream but not stored locally
+    Missing,
+    // Build is stored locally but no longer published upstream
+    Extra,
+    // Build files differ in size or checksum
+    Changed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LXCImageDiffEntry {
+    pub status: LXCImageDiffStatus,
+    pub dist: String,
+    pub release: String,
+    pub arch: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub path: String,
+    pub details: Vec<String>,
+}
+
+impl LXCImageDiffEntry {
+    pub fn of(
+        status: LXCImageDiffStatus,
+        image_metadata: &LXCImageMetadata,
+        path: &Path,
+        details: Vec<String>,
+    ) -> Self {
+        Self {
+            status,
+            dist: image_metadata.dist.clone(),
+            release: image_metadata.release.clone(),
+            arch: image_metadata.arch.clone(),
+            type_: image_metadata.type_.clone(),
+            name: image_metadata.name.clone(),
+            path: path.to_string_lossy().trim_end_matches('/').to_string(),
+            details,
+        }
+    }
+}
+
+impl TableRow for LXCImageDiffEntry {
+    const HEADER: &'static [&'static str] = &[
+        "STATUS", "DIST", "RELEASE", "ARCH", "TYPE", "NAME", "DETAILS",
+    ];
+
+    fn columns(&self) -> Vec<String> {
+        vec![
+            format!("{:?}", self.status).to_lowercase(),
+            self.dist.clone(),
+            self.release.clone(),
+            self.arch.clone(),
+            self.type_.clone(),
+            self.name.clone(),
+            self.details.join("; "),
+        ]
+    }
+}
+
+// Empty when the image has no SHA256SUMS
+pub async fn fetch_checksums(
+    client: &HttpClient,
+    image_url: &Url,
+) -> Result<HashMap<String, String>> {
+    let checksum_url = image_url.join(CHECKSUM_FILE)?;
+
+    if is_local(&checksum_url) {
+        if !local_path(&checksum_url)?.is_file() {
+            return Ok(HashMap::new());
+        }
+        return Ok(parse_checksums(&read_to_string(&checksum_url)?));
+    }
+
+    let response = client.get(&checksum_url).await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(HashMap::new());
+    }
+
+    Ok(parse_checksums(
+        &client.text(response.error_for_status()?).await?,
+    ))
+}
+
+// None when the server does not send Content-Length
+pub async fn remote_file_size(client: &HttpClient, url: &Url) -> Result<Option<u64>> {
+    if is_local(url) {
+        return Ok(Some(local_path(url)?.metadata()?.len()));
+    }
+
+    let response = client.head(url).await?.error_for_status()?;
+
+    Ok(response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok()?.parse().ok()))
+}
+
+pub async fn diff_image_files(
+    client: &HttpClient,
+    image_url: &Url,
+    image_dir: &Path,
+    image_files: &ImageFiles,
+    patched: bool,
+    checksum: bool,
+) -> Result<Vec<String>> {
+    let mut details = vec![];
+    let checksums = if checksum {
+        fetch_checksums(client, image_url).await?
+    } else {
+        HashMap::new()
+    };
+
+    for image_file in image_files {
+        let image_file_path = image_dir.join(image_file);
+
+        if !image_file_path.is_file() {
+            details.push(format!("{}: missing locally", image_file));
+            continue;
+        }
+
+        // Patched rootfs is expected to differ from the upstream one
+        if patched && image_file == "rootfs.tar.xz" {
+            continue;
+        }
+
+        let image_file_url = image_url.join(image_file)?;
+        let remote_size = remote_file_size(client, &image_file_url)
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Diff LXC image failed. Content length error. Url: '{}'",
+                    image_file_url
+                )
+            })?;
+        let local_size = image_file_path.metadata()?.len();
+
+        if remote_size != local_size {
+            details.push(format!(
+                "{}: size {} upstream, {} locally",
+                image_file, remote_size, local_size
+            ));
+            continue;
+        }
+
+        if let Some(remote_checksum) = checksums.get(image_file) {
+            if &sha256_file(&image_file_path)? != remote_checksum {
+                details.push(format!("{}: checksum mismatch", image_file));
+            }
+        }
+    }
+
+    Ok(details)
+}
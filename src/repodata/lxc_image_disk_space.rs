@@ -0,0 +1,78 @@
+use crate::http::HttpClient;
+
+use super::{
+    lxc_image_diff::remote_file_size, lxc_image_entries_cleanup::expired_image_entries,
+    lxc_image_metadata::LXCImageMetadata,
+};
+
+use anyhow::{Context, Result};
+use nix::sys::statvfs::statvfs;
+use slog_scope::warn;
+use std::{
+    collections::HashSet,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use url::Url;
+use walkdir::WalkDir;
+
+pub fn available_space(path: &Path) -> Result<u64> {
+    let stat = statvfs(path)
+        .with_context(|| format!("Failed to get free disk space. Path: {:?}", path))?;
+
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+// Sum of the upstream sizes of the image files to download. Files of unknown size are not counted
+pub async fn required_space(
+    client: &HttpClient,
+    image_url: &Url,
+    image_files: &[&String],
+) -> Result<u64> {
+    let mut required_space = 0;
+
+    for image_file in image_files {
+        let image_file_url = image_url.join(image_file)?;
+
+        match remote_file_size(client, &image_file_url).await {
+            Ok(size) => required_space += size.unwrap_or_default(),
+            // The download itself reports the error if the server is really broken
+            Err(err) => warn!(
+                "Get size of LXC image file failed with error: {:#}", err;
+                "url" => %image_file_url
+            ),
+        }
+    }
+
+    Ok(required_space)
+}
+
+// Bytes of the build that only come free when the store is pruned, the files linked from the
+// build and the store alone. A file shared by several removed builds counts at its last link
+pub fn store_held_space(image_dir: &Path) -> u64 {
+    WalkDir::new(image_dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file() && metadata.nlink() == 2)
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// Oldest builds first. The newest build of every image is never a candidate
+pub fn emergency_prune_candidates(
+    image_entries: Vec<(LXCImageMetadata, Duration)>,
+) -> Vec<PathBuf> {
+    let expired: HashSet<_> = expired_image_entries(1, image_entries.clone())
+        .into_iter()
+        .collect();
+
+    let mut candidates: Vec<_> = image_entries
+        .into_iter()
+        .filter(|(image_metadata, _)| expired.contains(&image_metadata.path))
+        .map(|(image_metadata, mtime)| (image_metadata.path, mtime))
+        .collect();
+    candidates.sort_by_key(|(_, mtime)| *mtime);
+
+    candidates.into_iter().map(|(path, _)| path).collect()
+}
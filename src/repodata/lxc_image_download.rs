@@ -1,39 +1,56 @@
-use anyhow::{anyhow, Result};
-use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::{http::HttpClient, metrics, progress};
+
+use super::lxc_image_throttle::Throttle;
+
+use anyhow::{bail, Result};
 use slog_scope::info;
-use std::{cmp::min, io::Write};
+use std::io::Write;
 use tempfile::{Builder, NamedTempFile};
 use url::Url;
 
-pub async fn download_image(config: &crate::config::Config, url: Url) -> Result<NamedTempFile> {
-    let response = reqwest::get(url.as_str()).await?;
-    let total_size = response.content_length().ok_or_else(|| {
-        anyhow!(
-            "Download LXC image failed. Content length error. Url: '{}'",
-            &url
-        )
-    })?;
-    let progress_bar = ProgressBar::new(total_size);
-    progress_bar.set_style(ProgressStyle::default_bar()
-			.template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/green}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")?
-			.progress_chars("#>-"));
-
-    info!("Download LXC image file '{}' started.", &url);
+pub async fn download_image(
+    config: &crate::config::Config,
+    client: &HttpClient,
+    throttle: &Throttle,
+    url: Url,
+) -> Result<NamedTempFile> {
+    // Rejected credentials must not end up as image files
+    let response = client.get(&url).await?.error_for_status()?;
+    // Missing with chunked transfer encoding and behind some caching proxies
+    let total_size = response.content_length();
+    let mut download_progress = progress::download(&url, total_size);
+    let download_throttle = throttle.download();
+
+    info!("Download LXC image file started."; "url" => %url);
 
     let mut tempfile = Builder::new().tempfile_in(&config.repodata.temporary_download_directory)?;
     let mut downloaded_bytes: u64 = 0;
     let mut stream = response.bytes_stream();
 
-    while let Some(item) = stream.next().await {
-        let chunk = item?;
+    while let Some(chunk) = client.next_chunk(&mut stream).await? {
         tempfile.write_all(&chunk)?;
-        let new_downloaded_bytes = min(downloaded_bytes + (chunk.len() as u64), total_size);
-        downloaded_bytes = new_downloaded_bytes;
-        progress_bar.set_position(new_downloaded_bytes);
+        metrics::add_downloaded_bytes(chunk.len() as u64);
+        downloaded_bytes += chunk.len() as u64;
+        download_progress.set_position(downloaded_bytes);
+        download_throttle.consume(chunk.len() as u64).await;
+    }
+
+    if let Some(total_size) = total_size {
+        if downloaded_bytes != total_size {
+            bail!(
+                "Download LXC image failed. Content length error. Expected {} bytes, received {} bytes. Url: '{}'",
+                total_size,
+                downloaded_bytes,
+                &url
+            );
+        }
     }
 
-    info!("Download LXC image file '{}' done.", &url);
+    info!(
+        "Download LXC image file done.";
+        "url" => %url,
+        "bytes" => downloaded_bytes
+    );
 
     Ok(tempfile)
 }
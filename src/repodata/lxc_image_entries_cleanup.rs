@@ -1,17 +1,117 @@
-use super::lxc_image_metadata::LXCImageMetadata;
+use super::{lxc_image_metadata::LXCImageMetadata, lxc_image_store::ImageStore};
 
-use anyhow::Result;
-use slog_scope::info;
-use std::{collections::HashMap, fs, path::PathBuf, time::Duration};
+use anyhow::{anyhow, bail, Context, Result};
+use slog_scope::{info, warn};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tempfile::Builder;
+use walkdir::WalkDir;
 
-pub fn cleanup_image_entries(
-    root_dir: &PathBuf,
-    number_of_container_to_backup: usize,
-    image_entries: Vec<(LXCImageMetadata, Duration)>,
+// Returns false when the directory is outside of root_dir and was left in place
+pub fn remove_image_entry(root_dir: &Path, image_dir: &Path) -> Result<bool> {
+    if !image_dir.canonicalize()?.starts_with(root_dir) {
+        warn!(
+            "Remove LXC image directory skipped. Directory is outside of {:?}: {:?}",
+            root_dir, image_dir;
+            "path" => %image_dir.display()
+        );
+        return Ok(false);
+    }
+
+    fs::remove_dir_all(image_dir)?;
+    info!(
+        "Remove LXC image directory. Directory path: {:?}",
+        image_dir;
+        "path" => %image_dir.display()
+    );
+
+    Ok(true)
+}
+
+// Copy of a build directory for a quarantine on another filesystem. File modes are kept
+fn copy_image_dir(image_dir: &Path, target_dir: &Path) -> Result<()> {
+    for entry in WalkDir::new(image_dir) {
+        let entry = entry?;
+        let target_path = target_dir.join(entry.path().strip_prefix(image_dir)?);
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target_path)?;
+            fs::set_permissions(&target_path, entry.metadata()?.permissions())?;
+        } else {
+            fs::copy(entry.path(), &target_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Move the build out of root_dir keeping its images/... layout instead of deleting it
+pub fn quarantine_image_entry(
+    root_dir: &Path,
+    image_dir: &Path,
+    quarantine_dir: &Path,
 ) -> Result<()> {
-    info!("Cleanup LXC images started.");
+    let image_dir_path = image_dir.canonicalize()?;
+    let relative_path = image_dir_path.strip_prefix(root_dir).with_context(|| {
+        format!(
+            "Quarantine LXC image directory failed. Directory is outside of {:?}: {:?}",
+            root_dir, image_dir
+        )
+    })?;
 
-    let root_dir = root_dir.canonicalize()?;
+    let quarantine_path = quarantine_dir.join(relative_path);
+    if quarantine_path.exists() {
+        bail!(
+            "Quarantine LXC image directory failed. Path already exists: {:?}",
+            quarantine_path
+        );
+    }
+
+    let quarantine_parent_path = quarantine_path
+        .parent()
+        .ok_or_else(|| anyhow!("Quarantine path has no parent. Path: {:?}", quarantine_path))?;
+    fs::create_dir_all(quarantine_parent_path)?;
+
+    match fs::rename(image_dir, &quarantine_path) {
+        Ok(()) => {}
+        // Quarantine on another filesystem. Copied next to the target and renamed into place
+        Err(err) if err.raw_os_error() == Some(nix::libc::EXDEV) => {
+            let quarantine_tempdir = Builder::new()
+                .prefix(".quarantine_")
+                .tempdir_in(quarantine_parent_path)?;
+            copy_image_dir(image_dir, quarantine_tempdir.path())?;
+            fs::rename(quarantine_tempdir.into_path(), &quarantine_path)?;
+            fs::remove_dir_all(image_dir)?;
+        }
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!(
+                    "Failed to move LXC image directory {:?} to {:?}",
+                    image_dir, quarantine_path
+                )
+            })
+        }
+    }
+
+    info!(
+        "Quarantine LXC image directory. Directory path: {:?}",
+        image_dir;
+        "path" => %image_dir.display(),
+        "quarantine_path" => %quarantine_path.display()
+    );
+
+    Ok(())
+}
+
+// Oldest builds of every image beyond number_of_container_to_backup, by directory mtime
+pub fn expired_image_entries(
+    number_of_container_to_backup: usize,
+    image_entries: Vec<(LXCImageMetadata, Duration)>,
+) -> Vec<PathBuf> {
     let hashed_image_entries: HashMap<_, Vec<_>> =
         image_entries
             .into_iter()
@@ -29,28 +129,80 @@ pub fn cleanup_image_entries(
                 acc
             });
 
+    let mut expired_image_entries = vec![];
+
     for mut image_entries in hashed_image_entries.into_values() {
         if image_entries.len() <= number_of_container_to_backup {
             continue;
         }
 
         let number_of_container_to_remove = image_entries.len() - number_of_container_to_backup;
-        image_entries.sort_by(|a, b| a.1.cmp(&b.1));
-
-        info!("cleanup_image_entries: {:#?}", image_entries);
-
-        for (removed_dir, _) in image_entries.iter().take(number_of_container_to_remove) {
-            if removed_dir.canonicalize()?.starts_with(&root_dir) {
-                fs::remove_dir_all(removed_dir)?;
-                info!(
-                    "Remove LXC image directory. Directory path: {:?}",
-                    removed_dir
-                );
-            }
-        }
+        image_entries.sort_by_key(|a| a.1);
+
+        expired_image_entries.extend(
+            image_entries
+                .into_iter()
+                .take(number_of_container_to_remove)
+                .map(|(path, _)| path),
+        );
     }
 
+    expired_image_entries.sort();
+    expired_image_entries
+}
+
+pub fn cleanup_image_entries(
+    root_dir: &Path,
+    number_of_container_to_backup: usize,
+    image_entries: Vec<(LXCImageMetadata, Duration)>,
+) -> Result<()> {
+    info!("Cleanup LXC images started.");
+
+    let root_dir = root_dir.canonicalize()?;
+    let expired_image_entries = expired_image_entries(number_of_container_to_backup, image_entries);
+
+    info!("cleanup_image_entries: {:#?}", expired_image_entries);
+
+    for removed_dir in &expired_image_entries {
+        remove_image_entry(&root_dir, removed_dir)?;
+    }
+    // Files shared with the remaining builds stay in the store
+    ImageStore::new(&root_dir).prune()?;
+
     info!("Cleanup LXC images done.");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::expired_image_entries;
+    use crate::repodata::lxc_image_metadata::LXCImageMetadata;
+    use std::{path::PathBuf, time::Duration};
+
+    #[test]
+    fn keep_newest_builds() {
+        let image_entries = [
+            ("7", "20230101_01:00", 3),
+            ("7", "20230201_01:00", 1),
+            ("7", "20230301_01:00", 2),
+            ("8", "20230101_01:00", 1),
+        ]
+        .into_iter()
+        .map(|(release, name, mtime)| {
+            (
+                LXCImageMetadata::of_parts("centos", release, "amd64", "default", name).unwrap(),
+                Duration::from_secs(mtime),
+            )
+        })
+        .collect();
+
+        assert_eq!(
+            expired_image_entries(1, image_entries),
+            vec![
+                PathBuf::from("images/centos/7/amd64/default/20230201_01:00"),
+                PathBuf::from("images/centos/7/amd64/default/20230301_01:00"),
+            ]
+        );
+    }
+}
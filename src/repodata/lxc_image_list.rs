@@ -0,0 +1,147 @@
+use super::lxc_image_metadata::LXCImageMetadata;
+
+use anyhow::{Context, Result};
+use indicatif::{HumanBytes, HumanDuration};
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+}
+
+pub trait TableRow {
+    const HEADER: &'static [&'static str];
+
+    fn columns(&self) -> Vec<String>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LXCImageListEntry {
+    pub dist: String,
+    pub release: String,
+    pub arch: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub name: String,
+    pub path: PathBuf,
+    // Total size of the image files in bytes
+    pub size: Option<u64>,
+    // Age of the image in seconds
+    pub age: Option<u64>,
+    pub pinned: bool,
+    pub patched: bool,
+}
+
+impl LXCImageListEntry {
+    pub fn of(
+        image_metadata: LXCImageMetadata,
+        size: Option<u64>,
+        age: Option<Duration>,
+        pinned: bool,
+        patched: bool,
+    ) -> Self {
+        Self {
+            dist: image_metadata.dist,
+            release: image_metadata.release,
+            arch: image_metadata.arch,
+            type_: image_metadata.type_,
+            name: image_metadata.name,
+            path: image_metadata.path,
+            size,
+            age: age.map(|age| age.as_secs()),
+            pinned,
+            patched,
+        }
+    }
+}
+
+impl TableRow for LXCImageListEntry {
+    const HEADER: &'static [&'static str] = &[
+        "DIST", "RELEASE", "ARCH", "TYPE", "NAME", "SIZE", "AGE", "PINNED", "PATCHED",
+    ];
+
+    fn columns(&self) -> Vec<String> {
+        vec![
+            self.dist.clone(),
+            self.release.clone(),
+            self.arch.clone(),
+            self.type_.clone(),
+            self.name.clone(),
+            self.size
+                .map(|size| HumanBytes(size).to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.age
+                .map(|age| HumanDuration(Duration::from_secs(age)).to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            if self.pinned { "yes" } else { "no" }.to_string(),
+            if self.patched { "yes" } else { "no" }.to_string(),
+        ]
+    }
+}
+
+// Hardlinked files, e.g. through the dedup store, count once over all builds sharing `seen`
+pub fn image_entry_size(image_dir: &Path, seen: &mut HashSet<(u64, u64)>) -> Result<u64> {
+    WalkDir::new(image_dir)
+        .into_iter()
+        .map(|entry| {
+            let metadata = entry?.metadata()?;
+            Ok(
+                if metadata.is_file() && seen.insert((metadata.dev(), metadata.ino())) {
+                    metadata.len()
+                } else {
+                    0
+                },
+            )
+        })
+        .sum()
+}
+
+fn format_table<T: TableRow>(image_list: &[T]) -> String {
+    let header = T::HEADER.iter().map(|column| column.to_string()).collect();
+    let rows: Vec<Vec<_>> = std::iter::once(header)
+        .chain(image_list.iter().map(T::columns))
+        .collect();
+
+    let mut widths = vec![0; T::HEADER.len()];
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(column, width)| format!("{:width$}", column, width = *width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+                + "\n"
+        })
+        .collect()
+}
+
+pub fn format_image_list<T: TableRow + Serialize>(
+    image_list: &[T],
+    output_format: OutputFormat,
+) -> Result<String> {
+    match output_format {
+        OutputFormat::Table => Ok(format_table(image_list)),
+        OutputFormat::Json => serde_json::to_string_pretty(image_list)
+            .map(|output| output + "\n")
+            .with_context(|| "Failed to format LXC image list as JSON"),
+        OutputFormat::Yaml => serde_yaml::to_string(image_list)
+            .with_context(|| "Failed to format LXC image list as YAML"),
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{anyhow, Result};
+use slog_scope::info;
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+use tempfile::{Builder, NamedTempFile};
+use url::Url;
+
+// FICLONE from linux/fs.h
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+pub fn is_local(url: &Url) -> bool {
+    url.scheme() == "file"
+}
+
+pub fn local_path(url: &Url) -> Result<PathBuf> {
+    url.to_file_path()
+        .map_err(|_| anyhow!("Convert url to local path error. Url: '{}'", url))
+}
+
+pub fn read_to_string(url: &Url) -> Result<String> {
+    Ok(fs::read_to_string(local_path(url)?)?)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CopyMethod {
+    Reflink,
+    Hardlink,
+    Copy,
+}
+
+impl CopyMethod {
+    pub fn name(&self) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+}
+
+fn reflink(source: &File, target: &File) -> io::Result<()> {
+    unsafe { ficlone(target.as_raw_fd(), source.as_raw_fd() as _) }?;
+    Ok(())
+}
+
+fn copy_file(source_path: &Path, target_path: &Path, hardlink: bool) -> io::Result<CopyMethod> {
+    let source = File::open(source_path)?;
+    let target = File::create(target_path)?;
+
+    if reflink(&source, &target).is_ok() {
+        return Ok(CopyMethod::Reflink);
+    }
+
+    drop(target);
+    fs::remove_file(target_path)?;
+
+    // Published files are made 0644, which must not change the mode of a linked source
+    let hardlink = hardlink && source.metadata()?.permissions().mode() & 0o7777 == 0o644;
+    if hardlink && fs::hard_link(source_path, target_path).is_ok() {
+        return Ok(CopyMethod::Hardlink);
+    }
+
+    fs::copy(source_path, target_path)?;
+    Ok(CopyMethod::Copy)
+}
+
+// Copy-on-write clone or hardlink when on the same filesystem, plain copy otherwise.
+// Hardlinked files share the inode with the source, so files that get patched are never hardlinked
+// and neither are sources with a mode other than 0644
+pub fn copy_to_tempfile(
+    config: &crate::config::Config,
+    source_path: &Path,
+    hardlink: bool,
+) -> Result<(NamedTempFile, CopyMethod)> {
+    let mut copy_method = CopyMethod::Copy;
+
+    let tempfile = Builder::new().make_in(
+        &config.repodata.temporary_download_directory,
+        |target_path| {
+            copy_method = copy_file(source_path, target_path, hardlink)?;
+            File::open(target_path)
+        },
+    )?;
+
+    Ok((tempfile, copy_method))
+}
+
+pub fn copy_image(
+    config: &crate::config::Config,
+    url: &Url,
+    hardlink: bool,
+) -> Result<NamedTempFile> {
+    let source_path = local_path(url)?;
+
+    info!("Copy LXC image file started."; "url" => %url);
+
+    let (tempfile, copy_method) = copy_to_tempfile(config, &source_path, hardlink)?;
+    let bytes = tempfile.as_file().metadata()?.len();
+
+    info!(
+        "Copy LXC image file done.";
+        "url" => %url,
+        "bytes" => bytes,
+        "method" => copy_method.name()
+    );
+
+    Ok(tempfile)
+}
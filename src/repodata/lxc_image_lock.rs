@@ -0,0 +1,29 @@
+use anyhow::{Context, Result};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+use std::{fs::File, os::unix::io::AsRawFd, path::Path};
+
+const LOCK_FILE: &str = ".lxc-tool.lock";
+
+// Exclusive lock of host_root_dir held while the tree is modified. Released on drop
+pub struct RepodataLock {
+    _file: File,
+}
+
+impl RepodataLock {
+    pub fn try_acquire(lock_dir: &Path) -> Result<Option<Self>> {
+        let lock_path = lock_dir.join(LOCK_FILE);
+        let file = File::create(&lock_path)
+            .with_context(|| format!("Failed to create lock file. Path: {:?}", lock_path))?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(Errno::EWOULDBLOCK) => Ok(None),
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to lock file. Path: {:?}", lock_path))
+            }
+        }
+    }
+}
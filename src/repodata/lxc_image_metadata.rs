@@ -1,7 +1,10 @@
 use crate::config::ImageFilter;
 
 use anyhow::{bail, Result};
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(Debug, Clone)]
 pub struct LXCImageMetadata {
@@ -35,6 +38,40 @@ pub fn of_metadata(input: &str) -> Result<LXCImageMetadata> {
     }
 }
 
+// Single path component that can neither escape host_root_dir nor break the index format
+pub fn path_component(value: &str) -> Result<&str> {
+    if value.is_empty() || value == "." || value == ".." || value.contains(['/', ';', '\0', '\n']) {
+        bail!("Invalid LXC image path component. Value: {:?}", value);
+    }
+
+    Ok(value)
+}
+
+impl LXCImageMetadata {
+    // Build stored locally under images/<dist>/<release>/<arch>/<type>/<name>/
+    pub fn of_parts(
+        dist: &str,
+        release: &str,
+        arch: &str,
+        type_: &str,
+        name: &str,
+    ) -> Result<LXCImageMetadata> {
+        let mut path = PathBuf::from("images");
+        for component in [dist, release, arch, type_, name] {
+            path.push(path_component(component)?);
+        }
+
+        Ok(LXCImageMetadata {
+            dist: dist.to_string(),
+            release: release.to_string(),
+            arch: arch.to_string(),
+            type_: type_.to_string(),
+            name: name.to_string(),
+            path,
+        })
+    }
+}
+
 impl LXCImageMetadata {
     pub fn get(&self, idx: &str) -> Option<String> {
         match idx {
@@ -45,6 +82,53 @@ pub fn get(&self, idx: &str) -> Option<String> {
             _ => None,
         }
     }
+
+    pub fn matches(&self, image_filter: &ImageFilter) -> bool {
+        image_filter
+            .clone()
+            .into_iter()
+            .all(|(key, value)| value == self.get(&key).unwrap_or_default())
+    }
+
+    pub fn is_pinned(&self, root_dir: &Path, pinned_images: &[PathBuf]) -> bool {
+        let path = self.path.strip_prefix(root_dir).unwrap_or(&self.path);
+
+        pinned_images
+            .iter()
+            .any(|pinned_image| pinned_image.strip_prefix("/").unwrap_or(pinned_image) == path)
+    }
+
+    // Build time encoded in the image name, e.g. 20230101_01:00
+    pub fn build_time(&self) -> Option<Duration> {
+        let (date, time) = self.name.split_once('_')?;
+        let (hour, minute) = time.split_once(':')?;
+
+        if date.len() != 8 {
+            return None;
+        }
+
+        let year: i64 = date.get(0..4)?.parse().ok()?;
+        let month: i64 = date.get(4..6)?.parse().ok()?;
+        let day: i64 = date.get(6..8)?.parse().ok()?;
+        let hour: u64 = hour.parse().ok()?;
+        let minute: u64 = minute.parse().ok()?;
+
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+            return None;
+        }
+
+        // Days since 1970-01-01 in the proleptic Gregorian calendar
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = u64::try_from(era * 146097 + day_of_era - 719468).ok()?;
+
+        Some(Duration::from_secs(
+            days * 86400 + hour * 3600 + minute * 60,
+        ))
+    }
 }
 
 pub trait FilterBy {
@@ -65,11 +149,7 @@ fn filter_by(
                 image_filters
                     .iter()
                     .filter_map(|image_filter| {
-                        let is_match = image_filter.clone().into_iter().all(|(key, value)| {
-                            value == lxc_container_metadata.get(&key).unwrap_or_default()
-                        });
-
-                        if is_match {
+                        if lxc_container_metadata.matches(image_filter) {
                             Some((
                                 lxc_container_metadata.clone(),
                                 image_filter.post_process.clone(),
@@ -89,3 +169,32 @@ fn filter_by(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::LXCImageMetadata;
+
+    use std::time::Duration;
+
+    fn image_metadata(name: &str) -> LXCImageMetadata {
+        LXCImageMetadata::of_metadata(&format!(
+            "centos;7;amd64;default;{};/images/centos/7/amd64/default/{}/",
+            name, name
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn build_time() {
+        assert_eq!(
+            image_metadata("19700101_00:00").build_time(),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            image_metadata("20230301_12:30").build_time(),
+            Some(Duration::from_secs(1677673800))
+        );
+        assert_eq!(image_metadata("20231301_12:30").build_time(), None);
+        assert_eq!(image_metadata("latest").build_time(), None);
+    }
+}
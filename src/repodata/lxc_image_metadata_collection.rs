@@ -1,4 +1,8 @@
-use crate::repodata::lxc_image_metadata::LXCImageMetadata;
+use crate::{
+    http::HttpClient,
+    repodata::lxc_image_local::{is_local, read_to_string},
+    repodata::lxc_image_metadata::LXCImageMetadata,
+};
 
 use anyhow::Result;
 use reqwest::Url;
@@ -13,18 +17,30 @@ pub fn of(url: &Url) -> Self {
         Self { url: url.clone() }
     }
 
-    pub async fn get(self) -> Result<Vec<LXCImageMetadata>> {
-        info!("Download LXC images metadata from '{}' started.", &self.url);
+    pub async fn get(self, client: &HttpClient) -> Result<Vec<LXCImageMetadata>> {
+        info!(
+            "Download LXC images metadata started.";
+            "url" => %self.url
+        );
 
-        let r = reqwest::get(self.url.clone())
-            .await?
-            .text()
-            .await?
+        let index = if is_local(&self.url) {
+            read_to_string(&self.url)?
+        } else {
+            client
+                .text(client.get(&self.url).await?.error_for_status()?)
+                .await?
+        };
+
+        let r = index
             .lines()
             .map(LXCImageMetadata::of_metadata)
             .collect::<Result<Vec<_>>>()?;
 
-        info!("Download LXC images metadata from '{}' done.", self.url);
+        info!(
+            "Download LXC images metadata done.";
+            "url" => %self.url,
+            "images" => r.len()
+        );
 
         Ok(r)
     }
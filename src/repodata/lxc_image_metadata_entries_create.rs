@@ -3,14 +3,12 @@
 use anyhow::Result;
 use regex::Regex;
 use std::{
-    path::PathBuf,
+    path::Path,
     time::{Duration, SystemTime},
 };
 use walkdir::WalkDir;
 
-pub fn create_image_metadata_entries(
-    root_dir: &PathBuf,
-) -> Result<Vec<(LXCImageMetadata, Duration)>> {
+pub fn create_image_metadata_entries(root_dir: &Path) -> Result<Vec<(LXCImageMetadata, Duration)>> {
     let re = Regex::new(r"/images/.+/.+/.+/.+/\d\d\d\d\d\d\d\d_\d\d:\d\d")?;
 
     let image_entries: Vec<_> = WalkDir::new(root_dir)
@@ -1,24 +1,58 @@
 use super::lxc_image_metadata::LXCImageMetadata;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use nix::unistd;
 use pwd::Passwd;
 use slog_scope::info;
 use std::{
-    fs::{self, File},
+    fs::{self, Permissions},
     io::Write,
-    path::PathBuf,
+    os::unix::prelude::PermissionsExt,
+    path::Path,
     time::Duration,
 };
+use tempfile::Builder;
+
+// Written next to the target and renamed, so readers never see a partially written index
+fn publish_index_file(path: &Path, content: &[u8], passwd: &Passwd) -> Result<()> {
+    let parent_dir_path = path
+        .parent()
+        .ok_or_else(|| anyhow!("Index file has no parent directory. Path: {:?}", path))?;
+    let mut tempfile = Builder::new()
+        .prefix(".index_")
+        .tempfile_in(parent_dir_path)?;
+
+    tempfile.write_all(content)?;
+    tempfile
+        .as_file()
+        .set_permissions(Permissions::from_mode(0o644))?;
+    unistd::chown(
+        tempfile.path(),
+        Some(passwd.uid.into()),
+        Some(passwd.gid.into()),
+    )?;
+    tempfile
+        .persist(path)
+        .with_context(|| format!("Failed to write index file. Path: {:?}", path))?;
+
+    Ok(())
+}
 
 pub fn save_image_metadata(
-    root_dir: &PathBuf,
+    root_dir: &Path,
     metadata_path: String,
     username: String,
     mut image_entries: Vec<(LXCImageMetadata, Duration)>,
 ) -> Result<()> {
     info!("Save LXC image metadata started.");
 
+    let Some(passwd) = Passwd::from_name(&username)? else {
+        bail!(
+            "Save LXC image metadata failed. Invalid username error. Username: {}",
+            username
+        );
+    };
+
     let image_metadata_path = root_dir.join(&metadata_path);
     if let Some(parent_dir_path) = &image_metadata_path.parent() {
         if !parent_dir_path.exists() {
@@ -26,10 +60,11 @@ pub fn save_image_metadata(
         }
     }
 
-    let mut file = File::create(&image_metadata_path)?;
-
     image_entries.sort_by(|a, b| a.0.name.cmp(&b.0.name).reverse());
 
+    let number_of_images = image_entries.len();
+    let mut index = String::new();
+
     for (image_metadata, _) in image_entries {
         let path = image_metadata.path;
         let root_dir_path = root_dir.to_str().ok_or_else(|| {
@@ -39,8 +74,8 @@ pub fn save_image_metadata(
             )
         })?;
 
-        file.write_all(
-            (format!(
+        index.push_str(
+            &(format!(
                 "{};{};{};{};{};/{:?}",
                 image_metadata.dist,
                 image_metadata.release,
@@ -50,37 +85,22 @@ pub fn save_image_metadata(
                 path.strip_prefix(root_dir_path)?,
             )
             .replace('\"', "")
-                + "\n")
-                .as_bytes(),
-        )?;
+                + "\n"),
+        );
     }
 
     let copy_image_metadata_path = image_metadata_path.with_extension("7");
     let user_image_metadata_file_path = image_metadata_path.with_file_name("index-user");
 
-    let file_copy_list = vec![copy_image_metadata_path, user_image_metadata_file_path];
-
-    for cp in file_copy_list.iter() {
-        fs::copy(&image_metadata_path, cp)?;
-    }
-
-    let mut index_files = file_copy_list;
+    let mut index_files = vec![copy_image_metadata_path, user_image_metadata_file_path];
 
     index_files.push(image_metadata_path);
 
-    match Passwd::from_name(&username)? {
-        Some(passwd) => {
-            for filepath in index_files {
-                unistd::chown(&filepath, Some(passwd.uid.into()), Some(passwd.gid.into()))?;
-            }
-        }
-        _ => bail!(
-            "Save LXC image metadata failed. Invalid username error. Username: {}",
-            username
-        ),
+    for filepath in index_files {
+        publish_index_file(&filepath, index.as_bytes(), &passwd)?;
     }
 
-    info!("Save LXC image metadata done.");
+    info!("Save LXC image metadata done."; "images" => number_of_images);
 
     Ok(())
 }
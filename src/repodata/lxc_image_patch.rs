@@ -14,7 +14,14 @@ pub fn patch_image(
     timeout: Timeout,
     metadata: &LXCImageMetadata,
 ) -> Result<()> {
-    info!("Patch LXC image started.");
+    info!(
+        "Patch LXC image started.";
+        "dist" => &metadata.dist,
+        "release" => &metadata.release,
+        "arch" => &metadata.arch,
+        "type" => &metadata.type_,
+        "name" => &metadata.name
+    );
 
     let tempfile = tempfile.path().to_str().ok_or_else(|| {
         anyhow!(
@@ -35,6 +42,10 @@ pub fn patch_image(
         .args(["-d", &metadata.dist])
         .args(["-r", &metadata.release])
         .args(["-a", &metadata.arch])
+        // The script must not talk to systemd on behalf of lxc-tool
+        .env_remove("NOTIFY_SOCKET")
+        .env_remove("WATCHDOG_USEC")
+        .env_remove("WATCHDOG_PID")
         .spawn()
         .map_err(|err| {
             anyhow!(
@@ -43,11 +54,18 @@ pub fn patch_image(
             )
         })?;
 
-    let timeout_sec = Duration::from_secs(timeout);
+    let wait_result = child.wait_timeout(Duration::from_secs(timeout));
 
-    match child.wait_timeout(timeout_sec) {
+    match wait_result {
         Ok(Some(status)) => {
-            info!("Patch LXC image done.");
+            info!(
+                "Patch LXC image done.";
+                "dist" => &metadata.dist,
+                "release" => &metadata.release,
+                "arch" => &metadata.arch,
+                "type" => &metadata.type_,
+                "name" => &metadata.name
+            );
             if !status.success() {
                 bail!("Patch LXC image completed with non-zero exit code.")
             }
@@ -0,0 +1,89 @@
+use crate::config;
+
+use super::{
+    lxc_image_checksum::{read_checksums, sha256_file, CHECKSUM_FILE},
+    lxc_image_metadata_entries_create::create_image_metadata_entries,
+    lxc_image_store::ImageStore,
+};
+
+use anyhow::Result;
+use std::{collections::HashMap, path::PathBuf};
+use walkdir::WalkDir;
+
+#[derive(Clone)]
+pub struct LocalFile {
+    pub path: PathBuf,
+    // Files of the seed directory are never hardlinked, the mirror must not share their inodes
+    pub in_mirror: bool,
+}
+
+// Local files by their SHA256 from the SHA256SUMS written next to the published builds and found
+// in the seed directory, plus the dedup store. Candidates are hashed before use, since a file may
+// have changed after its checksum was written
+pub struct LocalFiles {
+    store: ImageStore,
+    checksums: HashMap<String, Vec<LocalFile>>,
+}
+
+impl LocalFiles {
+    pub fn scan(config: &config::Config) -> Result<Self> {
+        let mut checksums: HashMap<String, Vec<LocalFile>> = HashMap::new();
+        let mut checksum_dirs: Vec<_> =
+            create_image_metadata_entries(&config.repodata.host_root_dir)?
+                .into_iter()
+                .map(|(image_metadata, _)| (image_metadata.path, true))
+                .collect();
+
+        if let Some(seed_directory) = &config.repodata.seed_directory {
+            checksum_dirs.extend(
+                WalkDir::new(seed_directory)
+                    .into_iter()
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| {
+                        entry.file_type().is_file() && entry.file_name() == CHECKSUM_FILE
+                    })
+                    .filter_map(|entry| Some((entry.path().parent()?.to_path_buf(), false))),
+            );
+        }
+
+        for (checksum_dir, in_mirror) in checksum_dirs {
+            for (file_name, sha256) in read_checksums(&checksum_dir)?.unwrap_or_default() {
+                checksums.entry(sha256).or_default().push(LocalFile {
+                    path: checksum_dir.join(file_name),
+                    in_mirror,
+                });
+            }
+        }
+
+        Ok(Self {
+            store: ImageStore::new(&config.repodata.host_root_dir),
+            checksums,
+        })
+    }
+
+    // Local file with exactly this content
+    pub async fn find(&self, sha256: &str) -> Option<LocalFile> {
+        let candidates: Vec<_> = self
+            .store
+            .get(sha256)
+            .map(|path| LocalFile {
+                path,
+                in_mirror: true,
+            })
+            .into_iter()
+            .chain(self.checksums.get(sha256).into_iter().flatten().cloned())
+            .collect();
+
+        for candidate in candidates {
+            let path = candidate.path.clone();
+            // Hashing a rootfs takes a while, keep it off the runtime threads
+            let path_sha256 = tokio::task::spawn_blocking(move || sha256_file(&path)).await;
+
+            if matches!(path_sha256, Ok(Ok(path_sha256)) if path_sha256 == sha256) {
+                return Some(candidate);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,144 @@
+use super::lxc_image_checksum::sha256_file;
+
+use anyhow::{anyhow, Context, Result};
+use slog_scope::info;
+use std::{
+    ffi::OsString,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+const STORE_DIR: &str = ".store/sha256";
+
+// Content-addressed files under host_root_dir. Identical image files are hardlinks of one entry
+pub struct ImageStore {
+    path: PathBuf,
+}
+
+impl ImageStore {
+    pub fn new(root_dir: &Path) -> Self {
+        Self {
+            path: root_dir.join(STORE_DIR),
+        }
+    }
+
+    fn entry_path(&self, sha256: &str) -> PathBuf {
+        self.path.join(&sha256[..2]).join(sha256)
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn get(&self, sha256: &str) -> Option<PathBuf> {
+        if sha256.len() < 2 {
+            return None;
+        }
+
+        let entry_path = self.entry_path(sha256);
+        entry_path.is_file().then_some(entry_path)
+    }
+
+    // Turns the file into a hardlink of the stored file with the same content, storing it first
+    // if the content is new. Returns true when the file was replaced by an existing entry
+    pub fn link(&self, file_path: &Path) -> Result<bool> {
+        self.link_with_checksum(file_path, &sha256_file(file_path)?)
+    }
+
+    pub fn link_with_checksum(&self, file_path: &Path, sha256: &str) -> Result<bool> {
+        let entry_path = self.entry_path(sha256);
+        if let Some(parent_dir_path) = entry_path.parent() {
+            fs::create_dir_all(parent_dir_path)?;
+        }
+
+        match fs::hard_link(file_path, &entry_path) {
+            Ok(()) => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!(
+                        "Failed to add file to the image store. Path: {:?}",
+                        file_path
+                    )
+                })
+            }
+        }
+
+        let file_metadata = fs::metadata(file_path)?;
+        let entry_metadata = fs::metadata(&entry_path)?;
+        if file_metadata.dev() == entry_metadata.dev()
+            && file_metadata.ino() == entry_metadata.ino()
+        {
+            return Ok(false);
+        }
+
+        // Linked next to the file and renamed over it, so the file never disappears
+        let file_name = file_path
+            .file_name()
+            .ok_or_else(|| anyhow!("Image file has no file name. Path: {:?}", file_path))?;
+        let mut link_name = OsString::from(".");
+        link_name.push(file_name);
+        link_name.push(".dedup");
+        let link_path = file_path.with_file_name(link_name);
+
+        let _ = fs::remove_file(&link_path);
+        fs::hard_link(&entry_path, &link_path)?;
+        fs::rename(&link_path, file_path)?;
+
+        Ok(true)
+    }
+
+    // Removes entries no longer linked from any build. Returns the number of bytes freed
+    pub fn prune(&self) -> Result<u64> {
+        if !self.path.exists() {
+            return Ok(0);
+        }
+
+        let mut freed = 0;
+
+        for entry in WalkDir::new(&self.path).min_depth(2).max_depth(2) {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            if metadata.is_file() && metadata.nlink() == 1 {
+                fs::remove_file(entry.path())?;
+                freed += metadata.len();
+            }
+        }
+
+        if freed > 0 {
+            info!("Prune LXC image store done."; "bytes" => freed);
+        }
+
+        Ok(freed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImageStore;
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    #[test]
+    fn link_and_prune() {
+        let root_dir = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(root_dir.path());
+        let first = root_dir.path().join("first");
+        let second = root_dir.path().join("second");
+        fs::write(&first, "meta").unwrap();
+        fs::write(&second, "meta").unwrap();
+
+        assert!(!store.link(&first).unwrap());
+        assert!(store.link(&second).unwrap());
+        assert!(!store.link(&second).unwrap());
+        assert_eq!(fs::metadata(&first).unwrap().nlink(), 3);
+        assert_eq!(fs::read_to_string(&second).unwrap(), "meta");
+
+        fs::remove_file(&first).unwrap();
+        assert_eq!(store.prune().unwrap(), 0);
+        fs::remove_file(&second).unwrap();
+        assert_eq!(store.prune().unwrap(), 4);
+    }
+}
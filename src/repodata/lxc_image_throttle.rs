@@ -0,0 +1,79 @@
+use crate::config::{Bandwidth, TimeOfDay};
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time::sleep;
+
+// Spaces out chunks so that the average rate stays below the limit
+#[derive(Default)]
+struct Pacer {
+    next_free: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    fn delay(&self, bytes: u64, rate: Option<u64>) -> Duration {
+        let mut next_free = self.next_free.lock().expect("Pacer lock");
+        let Some(rate) = rate.filter(|rate| *rate > 0) else {
+            *next_free = None;
+            return Duration::ZERO;
+        };
+
+        let now = Instant::now();
+        let start = next_free.map_or(now, |next_free| next_free.max(now));
+        let end = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        *next_free = Some(end);
+
+        end - now
+    }
+}
+
+// Bandwidth limits of one download run
+pub struct Throttle {
+    bandwidth: Bandwidth,
+    global: Pacer,
+}
+
+impl Throttle {
+    pub fn new(bandwidth: &Bandwidth) -> Self {
+        Self {
+            bandwidth: bandwidth.clone(),
+            global: Pacer::default(),
+        }
+    }
+
+    pub fn download(&self) -> DownloadThrottle<'_> {
+        DownloadThrottle {
+            throttle: self,
+            pacer: Pacer::default(),
+        }
+    }
+}
+
+pub struct DownloadThrottle<'a> {
+    throttle: &'a Throttle,
+    pacer: Pacer,
+}
+
+impl DownloadThrottle<'_> {
+    pub async fn consume(&self, bytes: u64) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let limit = self
+            .throttle
+            .bandwidth
+            .limit_at(TimeOfDay::of_unix_time(now.as_secs()));
+
+        let delay = self
+            .throttle
+            .global
+            .delay(bytes, limit.global)
+            .max(self.pacer.delay(bytes, limit.per_download));
+
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+    }
+}
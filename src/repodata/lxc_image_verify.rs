@@ -0,0 +1,144 @@
+use crate::config::ImageFiles;
+
+use super::{
+    lxc_image_checksum::{read_checksums, sha256_file},
+    lxc_image_metadata::LXCImageMetadata,
+};
+
+use anyhow::{Context, Result};
+use slog_scope::{info, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+fn verify_xz_archive(path: &Path) -> Result<bool> {
+    let status = Command::new("xz")
+        .arg("--test")
+        .arg("--")
+        .arg(path)
+        .status()
+        .with_context(|| "Verify LXC image failed. Run xz error.")?;
+
+    Ok(status.success())
+}
+
+pub fn verify_image_entry(
+    image_files: &ImageFiles,
+    image_metadata: &LXCImageMetadata,
+) -> Result<Vec<String>> {
+    let mut problems = vec![];
+    let checksums = read_checksums(&image_metadata.path)?;
+
+    for image_file in image_files {
+        let image_file_path = image_metadata.path.join(image_file);
+
+        if !image_file_path.is_file() {
+            problems.push(format!("File '{}' is missing", image_file));
+            continue;
+        }
+
+        if let Some(expected) = checksums.as_ref().and_then(|c| c.get(image_file)) {
+            let actual = sha256_file(&image_file_path)?;
+            if &actual != expected {
+                problems.push(format!(
+                    "File '{}' checksum mismatch. Expected: {}. Actual: {}",
+                    image_file, expected, actual
+                ));
+                continue;
+            }
+        }
+
+        if image_file.ends_with(".tar.xz") && !verify_xz_archive(&image_file_path)? {
+            problems.push(format!("File '{}' is not a valid xz archive", image_file));
+        }
+    }
+
+    Ok(problems)
+}
+
+pub fn verify_image_metadata_index(
+    root_dir: &Path,
+    metadata_path: &str,
+    image_entries: &[(LXCImageMetadata, Duration)],
+) -> Result<Vec<String>> {
+    let image_metadata_path = root_dir.join(metadata_path);
+    // Reported as problems instead of errors, so a repair still rewrites the index
+    let index = match fs::read_to_string(&image_metadata_path) {
+        Ok(index) => index,
+        Err(err) => {
+            return Ok(vec![format!(
+                "Index {:?} is not readable: {}",
+                image_metadata_path, err
+            )])
+        }
+    };
+
+    let mut problems = vec![];
+    let mut indexed_paths = HashSet::new();
+    for (line_number, line) in index.lines().enumerate() {
+        match LXCImageMetadata::of_metadata(line) {
+            Ok(image_metadata) => {
+                indexed_paths.insert(image_metadata.path);
+            }
+            Err(err) => problems.push(format!(
+                "Index line {} is malformed: {:#}",
+                line_number + 1,
+                err
+            )),
+        }
+    }
+    let local_paths = image_entries
+        .iter()
+        .map(|(image_metadata, _)| Ok(image_metadata.path.strip_prefix(root_dir)?.to_path_buf()))
+        .collect::<Result<HashSet<PathBuf>>>()?;
+
+    problems.extend(
+        indexed_paths
+            .difference(&local_paths)
+            .map(|path| format!("Index entry {:?} has no image directory", path))
+            .chain(
+                local_paths
+                    .difference(&indexed_paths)
+                    .map(|path| format!("Image directory {:?} is missing in index", path)),
+            ),
+    );
+    problems.sort();
+
+    Ok(problems)
+}
+
+pub fn verify_image_entries(
+    image_files: &ImageFiles,
+    image_entries: &[(LXCImageMetadata, Duration)],
+) -> Result<Vec<LXCImageMetadata>> {
+    info!("Verify LXC image files started.");
+
+    let mut broken_image_entries = vec![];
+
+    for (image_metadata, _) in image_entries {
+        let problems = verify_image_entry(image_files, image_metadata)?;
+
+        for problem in &problems {
+            warn!(
+                "Verify LXC image failed. {}. Directory path: {:?}",
+                problem, image_metadata.path
+            );
+        }
+
+        if !problems.is_empty() {
+            broken_image_entries.push(image_metadata.clone());
+        }
+    }
+
+    info!(
+        "Verify LXC image files done. Checked: {}. Broken: {}.",
+        image_entries.len(),
+        broken_image_entries.len()
+    );
+
+    Ok(broken_image_entries)
+}
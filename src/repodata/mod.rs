@@ -1,98 +1,1118 @@
+mod lxc_image_bundle;
+mod lxc_image_checksum;
+mod lxc_image_diff;
+mod lxc_image_disk_space;
 mod lxc_image_download;
 mod lxc_image_entries_cleanup;
+mod lxc_image_list;
+mod lxc_image_local;
+mod lxc_image_lock;
 mod lxc_image_metadata;
 mod lxc_image_metadata_collection;
 mod lxc_image_metadata_entries_create;
 mod lxc_image_metadata_save;
 mod lxc_image_patch;
+mod lxc_image_reuse;
+mod lxc_image_store;
+mod lxc_image_throttle;
+mod lxc_image_verify;
 
 use crate::{
-    config, repodata::lxc_image_download::download_image,
-    repodata::lxc_image_entries_cleanup::cleanup_image_entries,
+    config,
+    http::HttpClient,
+    metrics,
+    repodata::lxc_image_bundle::{export_bundle, import_bundle},
+    repodata::lxc_image_checksum::{sha256_file, write_checksums, CHECKSUM_FILE},
+    repodata::lxc_image_diff::{
+        diff_image_files, fetch_checksums, LXCImageDiffEntry, LXCImageDiffStatus,
+    },
+    repodata::lxc_image_disk_space::{
+        available_space, emergency_prune_candidates, required_space, store_held_space,
+    },
+    repodata::lxc_image_download::download_image,
+    repodata::lxc_image_entries_cleanup::{
+        cleanup_image_entries, expired_image_entries, quarantine_image_entry, remove_image_entry,
+    },
+    repodata::lxc_image_list::{format_image_list, image_entry_size, LXCImageListEntry},
+    repodata::lxc_image_local::{copy_image, copy_to_tempfile, is_local},
     repodata::lxc_image_metadata::FilterBy,
     repodata::lxc_image_metadata_collection::LXCImageMetadataCollection,
     repodata::lxc_image_metadata_entries_create::create_image_metadata_entries,
-    repodata::lxc_image_metadata_save::save_image_metadata, repodata::lxc_image_patch::patch_image,
+    repodata::lxc_image_metadata_save::save_image_metadata,
+    repodata::lxc_image_patch::patch_image,
+    repodata::lxc_image_reuse::LocalFiles,
+    repodata::lxc_image_store::ImageStore,
+    repodata::lxc_image_throttle::Throttle,
+    repodata::lxc_image_verify::{verify_image_entries, verify_image_metadata_index},
+    systemd,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use slog_scope::{error, info, warn};
 use std::{
+    collections::{HashMap, HashSet},
     fs::{self, Permissions},
     os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime},
 };
-use tempfile::Builder;
+use tempfile::{Builder, NamedTempFile};
+use url::Url;
+
+pub use lxc_image_list::OutputFormat;
+pub use lxc_image_lock::RepodataLock;
+pub use lxc_image_metadata::LXCImageMetadata;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static ABORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Stop downloading after the current image is published
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Roll back the image being published instead of moving it into place
+pub fn request_abort() {
+    ABORT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn try_lock(config: &config::Config) -> Result<Option<RepodataLock>> {
+    RepodataLock::try_acquire(&config.repodata.host_root_dir)
+}
+
+fn lock(config: &config::Config) -> Result<RepodataLock> {
+    try_lock(config)?.ok_or_else(|| {
+        anyhow!(
+            "Another lxc-tool process is modifying {:?}",
+            config.repodata.host_root_dir
+        )
+    })
+}
+
+fn unpinned_image_entries(
+    config: &config::Config,
+    image_entries: Vec<(LXCImageMetadata, Duration)>,
+) -> Vec<(LXCImageMetadata, Duration)> {
+    image_entries
+        .into_iter()
+        .filter(|(image_metadata, _)| {
+            !image_metadata.is_pinned(
+                &config.repodata.host_root_dir,
+                &config.repodata.pinned_images,
+            )
+        })
+        .collect()
+}
+
+fn is_patched(config: &config::Config, image_metadata: &LXCImageMetadata) -> bool {
+    config.repodata.image_filters.iter().any(|image_filter| {
+        image_filter.post_process.is_some() && image_metadata.matches(image_filter)
+    })
+}
 
 pub async fn download_images(config: config::Config) -> Result<()> {
+    let lock = lock(&config)?;
+
+    download_images_with_lock(config, &lock).await
+}
+
+async fn download_image_entry(
+    config: &config::Config,
+    client: &HttpClient,
+    throttle: &Throttle,
+    local_files: &LocalFiles,
+    lxc_image_metadata: &LXCImageMetadata,
+    post_process: &Option<PathBuf>,
+    image_dir_path: &Path,
+) -> Result<()> {
+    let image_dir = lxc_image_metadata.path.to_str().ok_or_else(|| {
+        anyhow!(
+            "Download LXC image failed. Convert path to string error. Path: {:?}",
+            lxc_image_metadata.path
+        )
+    })?;
+
+    let image_url = config.repodata.target_url.origin.join(image_dir)?;
+
+    let checksums = if is_local(&image_url) {
+        HashMap::new()
+    } else {
+        match fetch_checksums(client, &image_url).await {
+            Ok(checksums) => checksums,
+            // Reuse is an optimisation, the files are downloaded as without SHA256SUMS
+            Err(err) => {
+                warn!(
+                    "Get LXC image checksums failed with error: {:#}", err;
+                    "url" => %image_url
+                );
+                HashMap::new()
+            }
+        }
+    };
+
+    // Files of a local origin and reused local files are not downloaded, they need no space
+    let mut reused_files = HashMap::new();
+    let mut downloaded_files = vec![];
+    if !is_local(&image_url) {
+        for image_file in &config.repodata.image_files {
+            let local_file = match checksums.get(image_file) {
+                Some(sha256) => local_files.find(sha256).await,
+                None => None,
+            };
+            match local_file {
+                Some(local_file) => {
+                    reused_files.insert(image_file, local_file);
+                }
+                None => downloaded_files.push(image_file),
+            }
+        }
+    }
+
+    ensure_disk_space(config, client, &image_url, &downloaded_files).await?;
+
+    let mut tempfiles = vec![];
+
+    for image_file in &config.repodata.image_files {
+        let download_url = image_url.join(image_file)?;
+        // A patched rootfs must not modify the source
+        let hardlink = !(image_file == "rootfs.tar.xz" && post_process.is_some());
+
+        let tempfile = if is_local(&download_url) {
+            let config = config.clone();
+            tokio::task::spawn_blocking(move || copy_image(&config, &download_url, hardlink))
+                .await??
+        } else if let Some(local_file) = reused_files
+            .get(image_file)
+            // The emergency prune may have removed the build holding it
+            .filter(|local_file| local_file.path.is_file())
+        {
+            let (config, source_path) = (config.clone(), local_file.path.clone());
+            let hardlink = hardlink && local_file.in_mirror;
+            let (tempfile, copy_method) = tokio::task::spawn_blocking(move || {
+                copy_to_tempfile(&config, &source_path, hardlink)
+            })
+            .await??;
+            info!(
+                "Reuse local LXC image file done.";
+                "url" => %download_url,
+                "path" => %local_file.path.display(),
+                "method" => copy_method.name()
+            );
+            tempfile
+        } else {
+            download_image(config, client, throttle, download_url).await?
+        };
+
+        tempfiles.push((image_file.clone(), tempfile));
+    }
+
+    // Patching and hashing take a while, signals are handled by the runtime meanwhile
+    let config = config.clone();
+    let lxc_image_metadata = lxc_image_metadata.clone();
+    let post_process = post_process.clone();
+    let image_dir_path = image_dir_path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        publish_image_entry(
+            &config,
+            &lxc_image_metadata,
+            &post_process,
+            &image_dir_path,
+            tempfiles,
+        )
+    })
+    .await?
+}
+
+// Patch the rootfs if needed and move the files into place with a single rename of the directory
+fn publish_image_entry<S: AsRef<str>>(
+    config: &config::Config,
+    lxc_image_metadata: &LXCImageMetadata,
+    post_process: &Option<PathBuf>,
+    image_dir_path: &Path,
+    tempfiles: Vec<(S, NamedTempFile)>,
+) -> Result<()> {
+    let image_tempdir_path = Builder::new()
+        .prefix(".repodata_")
+        .tempdir_in(&config.repodata.temporary_download_directory)?;
+
+    if let Some(parent_dir_path) = image_dir_path.parent() {
+        fs::create_dir_all(parent_dir_path)?;
+    }
+
+    let mut checksums = vec![];
+
+    for (image_file, tempfile) in tempfiles {
+        let image_file = image_file.as_ref();
+        if image_file == "rootfs.tar.xz" {
+            if let Some(post_process) = post_process {
+                let patch_started = Instant::now();
+                patch_image(
+                    post_process,
+                    &tempfile,
+                    config.repodata.patcher_timeout,
+                    lxc_image_metadata,
+                )?;
+                metrics::observe_patch_duration(patch_started.elapsed());
+            }
+        }
+
+        let image_temp_path = image_tempdir_path.path().join(image_file);
+        fs::rename(&tempfile, &image_temp_path)?;
+        fs::set_permissions(&image_temp_path, Permissions::from_mode(0o644))?;
+
+        // The upstream checksum file does not match a patched rootfs, ours replaces it
+        if image_file == CHECKSUM_FILE {
+            continue;
+        }
+
+        let sha256 = sha256_file(&image_temp_path)?;
+        if config.repodata.dedup {
+            ImageStore::new(&config.repodata.host_root_dir)
+                .link_with_checksum(&image_temp_path, &sha256)?;
+        }
+        checksums.push((image_file.to_string(), sha256));
+    }
+
+    write_checksums(image_tempdir_path.path(), &checksums)?;
+    fs::set_permissions(
+        image_tempdir_path.path().join(CHECKSUM_FILE),
+        Permissions::from_mode(0o644),
+    )?;
+
+    if ABORT_REQUESTED.load(Ordering::SeqCst) {
+        bail!("Publish LXC image aborted. The image is rolled back.");
+    }
+
+    fs::rename(&image_tempdir_path, image_dir_path)?;
+    fs::set_permissions(image_dir_path, Permissions::from_mode(0o755))?;
+
+    Ok(())
+}
+
+// Fails before anything is downloaded when the image does not fit next to the reserve
+async fn ensure_disk_space(
+    config: &config::Config,
+    client: &HttpClient,
+    image_url: &Url,
+    image_files: &[&String],
+) -> Result<()> {
+    let temp_dir = &config.repodata.temporary_download_directory;
+    let required =
+        required_space(client, image_url, image_files).await? + config.repodata.disk_space.reserve;
+    let mut available = available_space(temp_dir)?;
+
+    if available >= required {
+        return Ok(());
+    }
+
+    if config.repodata.disk_space.emergency_prune {
+        warn!(
+            "Emergency prune of LXC images started. Not enough disk space.";
+            "required" => required,
+            "available" => available
+        );
+
+        let root_dir = config.repodata.host_root_dir.canonicalize()?;
+        let store = ImageStore::new(&root_dir);
+        let mut number_of_images = 0;
+        // Freed by the single prune of the store after the loop
+        let mut store_held = 0;
+
+        for image_dir_path in emergency_prune_candidates(unpinned_image_entries(
+            config,
+            create_image_metadata_entries(&config.repodata.host_root_dir)?,
+        )) {
+            let image_store_held = if store.exists() {
+                store_held_space(&image_dir_path)
+            } else {
+                0
+            };
+            if !remove_image_entry(&root_dir, &image_dir_path)? {
+                continue;
+            }
+            store_held += image_store_held;
+            number_of_images += 1;
+
+            available = available_space(temp_dir)?;
+            if available + store_held >= required {
+                break;
+            }
+        }
+
+        if number_of_images > 0 {
+            store.prune()?;
+            available = available_space(temp_dir)?;
+
+            // The index must not list the removed builds even if the run fails below
+            save_image_metadata(
+                &config.repodata.host_root_dir,
+                config.repodata.target_url.index_uri.clone(),
+                config.repodata.username.clone(),
+                create_image_metadata_entries(&config.repodata.host_root_dir)?,
+            )?;
+        }
+
+        warn!(
+            "Emergency prune of LXC images done.";
+            "images" => number_of_images,
+            "available" => available
+        );
+    }
+
+    if available < required {
+        bail!(
+            "Download LXC image failed. Not enough disk space in {:?}. Required {} bytes including the reserve of {} bytes, available {} bytes",
+            temp_dir,
+            required,
+            config.repodata.disk_space.reserve,
+            available
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn download_images_with_lock(config: config::Config, _lock: &RepodataLock) -> Result<()> {
+    let result = sync_images(&config).await;
+
+    if result.is_ok() {
+        metrics::set_last_success();
+    }
+
+    if config.metrics.listen.is_some() || config.metrics.textfile.is_some() {
+        if let Err(err) = metrics::refresh_image_groups(&config).await {
+            error!("Refresh LXC image stats failed with error: {:#}", err);
+        }
+    }
+
+    if let Some(textfile) = &config.metrics.textfile {
+        if let Err(err) = metrics::write_textfile(textfile, &config) {
+            error!("Write metrics textfile failed with error: {:#}", err);
+        }
+    }
+
+    result
+}
+
+async fn sync_images(config: &config::Config) -> Result<()> {
     let meta_data_url = config
         .repodata
         .target_url
         .origin
         .join(&config.repodata.target_url.index_uri)?;
 
+    let client = HttpClient::new(config)?;
+    let throttle = Throttle::new(&config.repodata.bandwidth);
+    let local_files = LocalFiles::scan(config)?;
+
     let lxc_image_metadata_collection = LXCImageMetadataCollection::of(&meta_data_url)
-        .get()
+        .get(&client)
         .await?
         .filter_by(&config.repodata.image_filters)?;
 
-    for (lxc_image_metadata, post_process) in lxc_image_metadata_collection {
-        let image_tempdir_path = Builder::new()
-            .prefix(".repodata_")
-            .tempdir_in(&config.repodata.temporary_download_directory)?;
+    let number_of_images = lxc_image_metadata_collection.len();
+
+    for (index, (lxc_image_metadata, post_process)) in
+        lxc_image_metadata_collection.into_iter().enumerate()
+    {
+        if is_shutdown_requested() {
+            info!("Download LXC images interrupted by shutdown request.");
+            break;
+        }
+
         let image_dir_path = &config.repodata.host_root_dir.join(&lxc_image_metadata.path);
 
         if image_dir_path.exists() {
+            metrics::image_skipped();
             continue;
         }
 
-        fs::create_dir_all(image_dir_path)?;
+        systemd::notify_status(&format!(
+            "Downloading LXC image {:?} ({}/{})",
+            lxc_image_metadata.path,
+            index + 1,
+            number_of_images
+        ));
+        info!(
+            "Download LXC image started.";
+            "dist" => &lxc_image_metadata.dist,
+            "release" => &lxc_image_metadata.release,
+            "arch" => &lxc_image_metadata.arch,
+            "type" => &lxc_image_metadata.type_,
+            "name" => &lxc_image_metadata.name
+        );
 
-        for image_file in &config.repodata.image_files {
-            let image_dir = lxc_image_metadata.path.to_str().ok_or_else(|| {
+        if let Err(err) = download_image_entry(
+            config,
+            &client,
+            &throttle,
+            &local_files,
+            &lxc_image_metadata,
+            &post_process,
+            image_dir_path,
+        )
+        .await
+        {
+            metrics::image_failed();
+            return Err(err);
+        }
+
+        metrics::image_downloaded();
+        info!(
+            "Download LXC image done.";
+            "dist" => &lxc_image_metadata.dist,
+            "release" => &lxc_image_metadata.release,
+            "arch" => &lxc_image_metadata.arch,
+            "type" => &lxc_image_metadata.type_,
+            "name" => &lxc_image_metadata.name
+        );
+    }
+
+    systemd::notify_status("Cleanup and save LXC images metadata");
+
+    cleanup_image_entries(
+        &config.repodata.host_root_dir,
+        config.repodata.number_of_container_to_backup,
+        unpinned_image_entries(
+            config,
+            create_image_metadata_entries(&config.repodata.host_root_dir)?,
+        ),
+    )?;
+
+    save_image_metadata(
+        &config.repodata.host_root_dir,
+        config.repodata.target_url.index_uri.clone(),
+        config.repodata.username.clone(),
+        create_image_metadata_entries(&config.repodata.host_root_dir)?,
+    )?;
+
+    Ok(())
+}
+
+pub struct ImageGroupStats {
+    pub dist: String,
+    pub release: String,
+    pub arch: String,
+    pub type_: String,
+    pub builds: u64,
+    pub size: u64,
+}
+
+pub fn image_group_stats(config: &config::Config) -> Result<Vec<ImageGroupStats>> {
+    let mut image_groups: HashMap<_, (u64, u64)> = HashMap::new();
+    let mut seen = HashSet::new();
+
+    for (image_metadata, _) in create_image_metadata_entries(&config.repodata.host_root_dir)? {
+        let size = image_entry_size(&image_metadata.path, &mut seen)?;
+        let image_group = image_groups
+            .entry((
+                image_metadata.dist,
+                image_metadata.release,
+                image_metadata.arch,
+                image_metadata.type_,
+            ))
+            .or_default();
+        image_group.0 += 1;
+        image_group.1 += size;
+    }
+
+    let mut image_groups: Vec<_> = image_groups
+        .into_iter()
+        .map(
+            |((dist, release, arch, type_), (builds, size))| ImageGroupStats {
+                dist,
+                release,
+                arch,
+                type_,
+                builds,
+                size,
+            },
+        )
+        .collect();
+    image_groups.sort_by(|a, b| {
+        (&a.dist, &a.release, &a.arch, &a.type_).cmp(&(&b.dist, &b.release, &b.arch, &b.type_))
+    });
+
+    Ok(image_groups)
+}
+
+pub async fn list_remote_images(
+    config: config::Config,
+    image_filter: Option<config::ImageFilter>,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let meta_data_url = config
+        .repodata
+        .target_url
+        .origin
+        .join(&config.repodata.target_url.index_uri)?;
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+
+    let client = HttpClient::new(&config)?;
+
+    let image_list: Vec<_> = LXCImageMetadataCollection::of(&meta_data_url)
+        .get(&client)
+        .await?
+        .into_iter()
+        .filter(|image_metadata| match &image_filter {
+            Some(image_filter) => image_metadata.matches(image_filter),
+            None => true,
+        })
+        .map(|image_metadata| {
+            let age = image_metadata
+                .build_time()
+                .map(|build_time| now.saturating_sub(build_time));
+            let pinned = image_metadata.is_pinned(
+                &config.repodata.host_root_dir,
+                &config.repodata.pinned_images,
+            );
+            let patched = is_patched(&config, &image_metadata);
+
+            LXCImageListEntry::of(image_metadata, None, age, pinned, patched)
+        })
+        .collect();
+
+    print!("{}", format_image_list(&image_list, output_format)?);
+
+    Ok(())
+}
+
+pub async fn list_local_images(
+    config: config::Config,
+    image_filter: Option<config::ImageFilter>,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let mut image_entries = create_image_metadata_entries(&config.repodata.host_root_dir)?;
+    image_entries.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+    let mut seen = HashSet::new();
+
+    let image_list = image_entries
+        .into_iter()
+        .filter(|(image_metadata, _)| match &image_filter {
+            Some(image_filter) => image_metadata.matches(image_filter),
+            None => true,
+        })
+        .map(|(image_metadata, mtime)| {
+            let size = image_entry_size(&image_metadata.path, &mut seen)?;
+            let pinned = image_metadata.is_pinned(
+                &config.repodata.host_root_dir,
+                &config.repodata.pinned_images,
+            );
+            let patched = is_patched(&config, &image_metadata);
+
+            Ok(LXCImageListEntry::of(
+                image_metadata,
+                Some(size),
+                Some(now.saturating_sub(mtime)),
+                pinned,
+                patched,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    print!("{}", format_image_list(&image_list, output_format)?);
+
+    Ok(())
+}
+
+// Returned by diff when the mirror differs from upstream, so monitoring can tell it from a failure
+#[derive(Debug)]
+pub struct ImagesDiffer(pub usize);
+
+impl std::fmt::Display for ImagesDiffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Diff LXC images found {} differences.", self.0)
+    }
+}
+
+impl std::error::Error for ImagesDiffer {}
+
+pub async fn diff_images(
+    config: config::Config,
+    checksum: bool,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let meta_data_url = config
+        .repodata
+        .target_url
+        .origin
+        .join(&config.repodata.target_url.index_uri)?;
+    let client = HttpClient::new(&config)?;
+
+    let remote_image_entries: HashMap<PathBuf, _> = LXCImageMetadataCollection::of(&meta_data_url)
+        .get(&client)
+        .await?
+        .filter_by(&config.repodata.image_filters)?
+        .into_iter()
+        .map(|(image_metadata, post_process)| {
+            (
+                image_metadata.path.clone(),
+                (image_metadata, post_process.is_some()),
+            )
+        })
+        .collect();
+    let local_image_entries: HashMap<PathBuf, _> =
+        create_image_metadata_entries(&config.repodata.host_root_dir)?
+            .into_iter()
+            .map(|(image_metadata, _)| {
+                Ok((
+                    image_metadata
+                        .path
+                        .strip_prefix(&config.repodata.host_root_dir)?
+                        .to_path_buf(),
+                    image_metadata,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+    let mut image_diff = vec![];
+
+    for (path, (image_metadata, patched)) in &remote_image_entries {
+        let Some(local_image_metadata) = local_image_entries.get(path) else {
+            image_diff.push(LXCImageDiffEntry::of(
+                LXCImageDiffStatus::Missing,
+                image_metadata,
+                path,
+                vec![],
+            ));
+            continue;
+        };
+
+        let image_dir = image_metadata.path.to_str().ok_or_else(|| {
+            anyhow!(
+                "Diff LXC image failed. Convert path to string error. Path: {:?}",
+                image_metadata.path
+            )
+        })?;
+        let details = diff_image_files(
+            &client,
+            &config.repodata.target_url.origin.join(image_dir)?,
+            &local_image_metadata.path,
+            &config.repodata.image_files,
+            *patched,
+            checksum,
+        )
+        .await?;
+
+        if !details.is_empty() {
+            image_diff.push(LXCImageDiffEntry::of(
+                LXCImageDiffStatus::Changed,
+                image_metadata,
+                path,
+                details,
+            ));
+        }
+    }
+
+    for (path, image_metadata) in &local_image_entries {
+        if !remote_image_entries.contains_key(path) {
+            image_diff.push(LXCImageDiffEntry::of(
+                LXCImageDiffStatus::Extra,
+                image_metadata,
+                path,
+                vec![],
+            ));
+        }
+    }
+
+    image_diff.sort_by(|a, b| a.path.cmp(&b.path));
+
+    print!("{}", format_image_list(&image_diff, output_format)?);
+
+    if !image_diff.is_empty() {
+        return Err(ImagesDiffer(image_diff.len()).into());
+    }
+
+    Ok(())
+}
+
+pub async fn verify_images(config: config::Config, repair: bool) -> Result<()> {
+    let image_entries = create_image_metadata_entries(&config.repodata.host_root_dir)?;
+
+    let broken_image_entries = verify_image_entries(&config.repodata.image_files, &image_entries)?;
+    let index_problems = verify_image_metadata_index(
+        &config.repodata.host_root_dir,
+        &config.repodata.target_url.index_uri,
+        &image_entries,
+    )?;
+
+    for problem in &index_problems {
+        warn!("Verify LXC images metadata failed. {}.", problem);
+    }
+
+    if broken_image_entries.is_empty() && index_problems.is_empty() {
+        return Ok(());
+    }
+
+    if !repair {
+        bail!(
+            "Verify LXC images failed. Broken images: {}. Index problems: {}.",
+            broken_image_entries.len(),
+            index_problems.len()
+        );
+    }
+
+    info!("Repair LXC images started.");
+
+    let lock = lock(&config)?;
+    let root_dir = config.repodata.host_root_dir.canonicalize()?;
+    for image_metadata in &broken_image_entries {
+        remove_image_entry(&root_dir, &image_metadata.path)?;
+    }
+
+    // Missing images are downloaded again and the index is rewritten from the on-disk tree
+    download_images_with_lock(config, &lock).await?;
+
+    info!("Repair LXC images done.");
+
+    Ok(())
+}
+
+pub async fn export_images(
+    config: config::Config,
+    image_filter: Option<config::ImageFilter>,
+    bundle_path: &Path,
+) -> Result<()> {
+    let mut image_entries: Vec<_> = create_image_metadata_entries(&config.repodata.host_root_dir)?
+        .into_iter()
+        .filter(|(image_metadata, _)| match &image_filter {
+            Some(image_filter) => image_metadata.matches(image_filter),
+            None => true,
+        })
+        .collect();
+    image_entries.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+
+    if image_entries.is_empty() {
+        bail!("Export LXC images failed. No local images match the filter.");
+    }
+
+    let number_of_images = export_bundle(&image_entries, bundle_path)?;
+
+    info!(
+        "Export LXC images bundle done.";
+        "path" => %bundle_path.display(),
+        "images" => number_of_images
+    );
+
+    Ok(())
+}
+
+pub async fn import_images(config: config::Config, bundle_path: &Path) -> Result<()> {
+    let _lock = lock(&config)?;
+
+    let number_of_images = import_bundle(
+        bundle_path,
+        &config.repodata.temporary_download_directory,
+        &config.repodata.host_root_dir,
+        config.repodata.dedup,
+    )?;
+
+    info!(
+        "Import LXC images bundle done.";
+        "path" => %bundle_path.display(),
+        "images" => number_of_images
+    );
+
+    cleanup_image_entries(
+        &config.repodata.host_root_dir,
+        config.repodata.number_of_container_to_backup,
+        unpinned_image_entries(
+            &config,
+            create_image_metadata_entries(&config.repodata.host_root_dir)?,
+        ),
+    )?;
+
+    save_image_metadata(
+        &config.repodata.host_root_dir,
+        config.repodata.target_url.index_uri.clone(),
+        config.repodata.username.clone(),
+        create_image_metadata_entries(&config.repodata.host_root_dir)?,
+    )?;
+
+    Ok(())
+}
+
+pub async fn add_image(
+    config: config::Config,
+    lxc_image_metadata: LXCImageMetadata,
+    rootfs_path: &Path,
+    meta_path: &Path,
+    patch: bool,
+) -> Result<()> {
+    if lxc_image_metadata.build_time().is_none() {
+        bail!(
+            "Add LXC image failed. Invalid image name {:?}. Expected format YYYYMMDD_HH:MM",
+            lxc_image_metadata.name
+        );
+    }
+
+    let post_process = if patch {
+        let post_process = config
+            .repodata
+            .image_filters
+            .iter()
+            .find(|image_filter| {
+                image_filter.post_process.is_some() && lxc_image_metadata.matches(image_filter)
+            })
+            .and_then(|image_filter| image_filter.post_process.clone());
+
+        if post_process.is_none() {
+            bail!("Add LXC image failed. No image filter with post_process matches the image.");
+        }
+        post_process
+    } else {
+        None
+    };
+
+    let _lock = lock(&config)?;
+
+    let image_dir_path = config.repodata.host_root_dir.join(&lxc_image_metadata.path);
+    if image_dir_path.exists() {
+        bail!(
+            "Add LXC image failed. Image already exists. Path: {:?}",
+            image_dir_path
+        );
+    }
+
+    info!(
+        "Add LXC image started.";
+        "dist" => &lxc_image_metadata.dist,
+        "release" => &lxc_image_metadata.release,
+        "arch" => &lxc_image_metadata.arch,
+        "type" => &lxc_image_metadata.type_,
+        "name" => &lxc_image_metadata.name
+    );
+
+    let tempfiles = [("rootfs.tar.xz", rootfs_path), ("meta.tar.xz", meta_path)]
+        .into_iter()
+        .map(|(image_file, source_path)| {
+            let source_url = Url::from_file_path(source_path.canonicalize()?).map_err(|_| {
                 anyhow!(
-                    "Download LXC image failed. Convert path to string error. Path: {:?}",
-                    lxc_image_metadata.path
+                    "Add LXC image failed. Convert path to url error. Path: {:?}",
+                    source_path
                 )
             })?;
-            let download_url = config
-                .repodata
-                .target_url
-                .origin
-                .join(image_dir)?
-                .join(image_file)?;
-            let tempfile = download_image(&config, download_url).await?;
-
-            if image_file == "rootfs.tar.xz" {
-                if let Some(post_process) = &post_process {
-                    patch_image(
-                        post_process,
-                        &tempfile,
-                        config.repodata.patcher_timeout,
-                        &lxc_image_metadata,
-                    )?;
-                }
+
+            // Never hardlinked, the permissions of the published file would change the source
+            Ok((image_file, copy_image(&config, &source_url, false)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    publish_image_entry(
+        &config,
+        &lxc_image_metadata,
+        &post_process,
+        &image_dir_path,
+        tempfiles,
+    )?;
+
+    info!(
+        "Add LXC image done.";
+        "dist" => &lxc_image_metadata.dist,
+        "release" => &lxc_image_metadata.release,
+        "arch" => &lxc_image_metadata.arch,
+        "type" => &lxc_image_metadata.type_,
+        "name" => &lxc_image_metadata.name
+    );
+
+    save_image_metadata(
+        &config.repodata.host_root_dir,
+        config.repodata.target_url.index_uri.clone(),
+        config.repodata.username.clone(),
+        create_image_metadata_entries(&config.repodata.host_root_dir)?,
+    )?;
+
+    Ok(())
+}
+
+// Selects builds by <dist>/<release>/<arch>/<type>[/<name>]
+pub async fn remove_images(
+    config: config::Config,
+    image_spec: &str,
+    quarantine_dir: Option<&Path>,
+) -> Result<()> {
+    let image_spec: Vec<_> = image_spec.trim_matches('/').split('/').collect();
+    if !(4..=5).contains(&image_spec.len()) {
+        bail!(
+            "Remove LXC images failed. Expected <dist>/<release>/<arch>/<type>[/<name>], got {:?}",
+            image_spec.join("/")
+        );
+    }
+
+    let _lock = lock(&config)?;
+    let root_dir = config.repodata.host_root_dir.canonicalize()?;
+
+    let image_entries: Vec<_> = create_image_metadata_entries(&config.repodata.host_root_dir)?
+        .into_iter()
+        .filter(|(image_metadata, _)| {
+            [
+                &image_metadata.dist,
+                &image_metadata.release,
+                &image_metadata.arch,
+                &image_metadata.type_,
+                &image_metadata.name,
+            ]
+            .into_iter()
+            .zip(&image_spec)
+            .all(|(value, selected)| value == selected)
+        })
+        .collect();
+
+    if image_entries.is_empty() {
+        bail!(
+            "Remove LXC images failed. No local images match {:?}",
+            image_spec.join("/")
+        );
+    }
+
+    let mut number_of_images = 0;
+    let mut result = Ok(());
+
+    for (image_metadata, _) in &image_entries {
+        if image_metadata.is_pinned(
+            &config.repodata.host_root_dir,
+            &config.repodata.pinned_images,
+        ) {
+            warn!(
+                "Remove LXC image skipped. Image is pinned.";
+                "path" => %image_metadata.path.display()
+            );
+            continue;
+        }
+
+        let removed = match quarantine_dir {
+            Some(quarantine_dir) => {
+                quarantine_image_entry(&root_dir, &image_metadata.path, quarantine_dir)
+                    .map(|()| true)
+            }
+            None => remove_image_entry(&root_dir, &image_metadata.path),
+        };
+        match removed {
+            Ok(true) => number_of_images += 1,
+            Ok(false) => {}
+            Err(err) => {
+                result = Err(err);
+                break;
             }
+        }
+    }
 
-            let image_temp_path = image_tempdir_path.path().join(image_file);
-            fs::rename(&tempfile, &image_temp_path)?;
-            fs::set_permissions(image_temp_path, Permissions::from_mode(0o644))?;
+    // The index follows the builds already removed, also when a later one failed
+    ImageStore::new(&root_dir).prune()?;
+
+    save_image_metadata(
+        &config.repodata.host_root_dir,
+        config.repodata.target_url.index_uri.clone(),
+        config.repodata.username.clone(),
+        create_image_metadata_entries(&config.repodata.host_root_dir)?,
+    )?;
+
+    result?;
+
+    if number_of_images == 0 {
+        bail!(
+            "Remove LXC images failed. All local images matching {:?} are pinned or outside of host_root_dir",
+            image_spec.join("/")
+        );
+    }
+
+    Ok(())
+}
+
+// Rewrites the index files from the on-disk tree without syncing. The metrics textfile is left to
+// download runs, a fresh process would reset its counters
+pub async fn reindex_images(config: config::Config) -> Result<()> {
+    let _lock = lock(&config)?;
+
+    save_image_metadata(
+        &config.repodata.host_root_dir,
+        config.repodata.target_url.index_uri.clone(),
+        config.repodata.username.clone(),
+        create_image_metadata_entries(&config.repodata.host_root_dir)?,
+    )?;
+
+    Ok(())
+}
+
+// Applies the retention policy without contacting the upstream server
+pub async fn prune_images(config: config::Config, dry_run: bool) -> Result<()> {
+    if dry_run {
+        let expired_image_entries = expired_image_entries(
+            config.repodata.number_of_container_to_backup,
+            unpinned_image_entries(
+                &config,
+                create_image_metadata_entries(&config.repodata.host_root_dir)?,
+            ),
+        );
+
+        for image_dir_path in expired_image_entries {
+            println!(
+                "{}",
+                image_dir_path
+                    .strip_prefix(&config.repodata.host_root_dir)
+                    .unwrap_or(&image_dir_path)
+                    .display()
+            );
         }
 
-        fs::rename(&image_tempdir_path, image_dir_path)?;
-        fs::set_permissions(image_dir_path, Permissions::from_mode(0o755))?;
+        return Ok(());
     }
 
+    let _lock = lock(&config)?;
+
     cleanup_image_entries(
         &config.repodata.host_root_dir,
         config.repodata.number_of_container_to_backup,
-        create_image_metadata_entries(&config.repodata.host_root_dir)?,
+        unpinned_image_entries(
+            &config,
+            create_image_metadata_entries(&config.repodata.host_root_dir)?,
+        ),
     )?;
 
     save_image_metadata(
         &config.repodata.host_root_dir,
-        config.repodata.target_url.index_uri,
-        config.repodata.username,
+        config.repodata.target_url.index_uri.clone(),
+        config.repodata.username.clone(),
         create_image_metadata_entries(&config.repodata.host_root_dir)?,
     )?;
 
     Ok(())
 }
+
+// Hardlinks identical files of the existing builds through the content-hash store
+pub async fn dedup_images(config: config::Config) -> Result<()> {
+    let _lock = lock(&config)?;
+    let store = ImageStore::new(&config.repodata.host_root_dir);
+
+    let mut number_of_files = 0;
+    let mut bytes = 0;
+
+    for (image_metadata, _) in create_image_metadata_entries(&config.repodata.host_root_dir)? {
+        for entry in fs::read_dir(&image_metadata.path)? {
+            let file_path = entry?.path();
+            if !file_path.is_file() {
+                continue;
+            }
+
+            let size = file_path.metadata()?.len();
+            if store.link(&file_path)? {
+                number_of_files += 1;
+                bytes += size;
+            }
+        }
+    }
+
+    info!(
+        "Dedup LXC images done.";
+        "files" => number_of_files,
+        "bytes" => bytes
+    );
+
+    Ok(())
+}
@@ -0,0 +1,469 @@
+use crate::systemd;
+
+use anyhow::{Context, Result};
+use futures_util::stream;
+use hyper::{
+    header::{self, HeaderValue},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use slog_scope::info;
+use std::{
+    convert::Infallible,
+    fs::Metadata,
+    io::SeekFrom,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+    signal::unix::{signal, SignalKind},
+};
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+// Only a single range is supported. Multiple ranges are served as a full response
+fn parse_range(range: &str, len: u64) -> Option<ByteRange> {
+    let range = range.trim().strip_prefix("bytes=")?;
+
+    if range.contains(',') {
+        return None;
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?),
+    };
+
+    if start > end || start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+
+    Some(ByteRange::Satisfiable(start, end.min(len - 1)))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let file_name = path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .unwrap_or_default();
+
+    if file_name.starts_with("index-") || file_name == "SHA256SUMS" {
+        return "text/plain; charset=utf-8";
+    }
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("xz") => "application/x-xz",
+        Some("gz") => "application/gzip",
+        Some("json") => "application/json",
+        Some("asc") => "application/pgp-signature",
+        Some("html") => "text/html; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn etag(metadata: &Metadata) -> String {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    format!("\"{:x}-{:x}\"", mtime.as_secs(), metadata.len())
+}
+
+fn is_not_modified(request: &Request<Body>, etag: &str, mtime: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.headers().get(header::IF_NONE_MATCH) {
+        return if_none_match
+            .to_str()
+            .map(|if_none_match| {
+                if_none_match
+                    .split(',')
+                    .any(|tag| tag.trim() == etag || tag.trim() == "*")
+            })
+            .unwrap_or(false);
+    }
+
+    let if_modified_since = request
+        .headers()
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|if_modified_since| if_modified_since.to_str().ok())
+        .and_then(|if_modified_since| httpdate::parse_http_date(if_modified_since).ok());
+
+    match (if_modified_since, mtime) {
+        // HTTP dates have a one second resolution
+        (Some(if_modified_since), Some(mtime)) => {
+            mtime < if_modified_since + Duration::from_secs(1)
+        }
+        _ => false,
+    }
+}
+
+fn file_body(file: File, remaining: u64) -> Body {
+    Body::wrap_stream(stream::unfold(
+        (file, remaining),
+        |(mut file, mut remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+
+            let mut buffer = vec![0; READ_BUFFER_SIZE.min(remaining as usize)];
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buffer.truncate(n);
+                    remaining -= n as u64;
+                    Some((Ok::<_, std::io::Error>(buffer), (file, remaining)))
+                }
+                Err(err) => Some((Err(err), (file, 0))),
+            }
+        },
+    ))
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(format!("{}\n", status)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn directory_response(request_path: &str, dir_path: &Path) -> Result<Response<Body>> {
+    let mut names = vec![];
+    let mut entries = fs::read_dir(dir_path).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type().await?.is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let body = names
+        .iter()
+        .map(|name| {
+            let name = html_escape(name);
+            format!("<a href=\"{}\">{}</a>\n", name, name)
+        })
+        .collect::<String>();
+    let body = format!(
+        "<html><head><title>Index of {}</title></head><body><h1>Index of {}</h1><pre>\n<a href=\"../\">../</a>\n{}</pre></body></html>\n",
+        html_escape(request_path),
+        html_escape(request_path),
+        body
+    );
+
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    Ok(response)
+}
+
+async fn file_response(request: &Request<Body>, file_path: &Path) -> Result<Response<Body>> {
+    let mut file = File::open(file_path).await?;
+    let metadata = file.metadata().await?;
+    let len = metadata.len();
+    let etag = etag(&metadata);
+    let mtime = metadata.modified().ok();
+
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag)?);
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(file_path)),
+    );
+    if let Some(mtime) = mtime {
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&httpdate::fmt_http_date(mtime))?,
+        );
+    }
+
+    if is_not_modified(request, &etag, mtime) {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(response);
+    }
+
+    // Range is ignored when If-Range does not match the current file
+    let if_range_matches = request
+        .headers()
+        .get(header::IF_RANGE)
+        .map(|if_range| {
+            if_range
+                .to_str()
+                .map(|if_range| if_range == etag)
+                .unwrap_or(false)
+        })
+        .unwrap_or(true);
+    let range = request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .filter(|_| if_range_matches)
+        .and_then(|range| parse_range(range, len));
+
+    let (start, content_length) = match range {
+        Some(ByteRange::Satisfiable(start, end)) => {
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            response.headers_mut().insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len))?,
+            );
+            (start, end - start + 1)
+        }
+        Some(ByteRange::Unsatisfiable) => {
+            let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+            response.headers_mut().insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len))?,
+            );
+            return Ok(response);
+        }
+        None => (0, len),
+    };
+
+    response
+        .headers_mut()
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+
+    if request.method() == Method::GET {
+        file.seek(SeekFrom::Start(start)).await?;
+        *response.body_mut() = file_body(file, content_length);
+    }
+
+    Ok(response)
+}
+
+fn resolve_path(root_dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let request_path = percent_decode_str(request_path).decode_utf8().ok()?;
+    let mut path = root_dir.to_path_buf();
+
+    for component in Path::new(request_path.as_ref()).components() {
+        match component {
+            // Hidden like in the listing, e.g. the dedup store and the temporary directories
+            Component::Normal(component) if component.to_string_lossy().starts_with('.') => {
+                return None
+            }
+            Component::Normal(component) => path.push(component),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(path)
+}
+
+async fn route(root_dir: &Path, listing: bool, request: &Request<Body>) -> Result<Response<Body>> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+        return Ok(response);
+    }
+
+    let request_path = request.uri().path();
+    let path = match resolve_path(root_dir, request_path) {
+        Some(path) => path,
+        None => return Ok(status_response(StatusCode::BAD_REQUEST)),
+    };
+
+    // Symlinks must not lead outside of root_dir
+    let path = match fs::canonicalize(&path).await {
+        Ok(path) if path.starts_with(root_dir) => path,
+        _ => return Ok(status_response(StatusCode::NOT_FOUND)),
+    };
+
+    if fs::metadata(&path).await?.is_file() {
+        return file_response(request, &path).await;
+    }
+
+    if !request_path.ends_with('/') {
+        let mut response = status_response(StatusCode::MOVED_PERMANENTLY);
+        response.headers_mut().insert(
+            header::LOCATION,
+            HeaderValue::from_str(&format!("{}/", request_path))?,
+        );
+        return Ok(response);
+    }
+
+    if listing {
+        directory_response(request_path, &path).await
+    } else {
+        Ok(status_response(StatusCode::FORBIDDEN))
+    }
+}
+
+async fn handle(
+    root_dir: Arc<PathBuf>,
+    remote_addr: SocketAddr,
+    listing: bool,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = route(&root_dir, listing, &request)
+        .await
+        .unwrap_or_else(|err| {
+            info!("Serve LXC images request failed. Error: {:#}", err);
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        });
+
+    let content_length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .unwrap_or("-");
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .unwrap_or("-");
+    info!(
+        "{} \"{} {} {:?}\" {} {} \"{}\"",
+        remote_addr.ip(),
+        request.method(),
+        request.uri(),
+        request.version(),
+        response.status().as_u16(),
+        content_length,
+        user_agent
+    );
+
+    Ok(response)
+}
+
+pub async fn serve(root_dir: &Path, listen: SocketAddr, listing: bool) -> Result<()> {
+    let root_dir = Arc::new(
+        root_dir
+            .canonicalize()
+            .with_context(|| format!("Failed to canonicalize {:?}", root_dir))?,
+    );
+
+    info!(
+        "Serve LXC images from {:?} on '{}' started.",
+        root_dir, listen
+    );
+
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let root_dir = root_dir.clone();
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(root_dir.clone(), remote_addr, listing, request)
+            }))
+        }
+    });
+
+    let server =
+        Server::try_bind(&listen).with_context(|| format!("Failed to listen on '{}'", listen))?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    systemd::notify_ready();
+
+    server
+        .serve(make_service)
+        .with_graceful_shutdown(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        })
+        .await?;
+
+    info!("Serve LXC images done.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range, resolve_path, ByteRange};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn range() {
+        assert_eq!(
+            parse_range("bytes=0-99", 1000),
+            Some(ByteRange::Satisfiable(0, 99))
+        );
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            Some(ByteRange::Satisfiable(900, 999))
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            Some(ByteRange::Satisfiable(900, 999))
+        );
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            Some(ByteRange::Satisfiable(500, 999))
+        );
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            Some(ByteRange::Unsatisfiable)
+        );
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn path() {
+        let root_dir = Path::new("/srv/lxc");
+
+        assert_eq!(
+            resolve_path(root_dir, "/images/centos/7/amd64/default/rootfs.tar.xz"),
+            Some(PathBuf::from(
+                "/srv/lxc/images/centos/7/amd64/default/rootfs.tar.xz"
+            ))
+        );
+        assert_eq!(
+            resolve_path(root_dir, "/meta/1.0/index%2Dsystem"),
+            Some(PathBuf::from("/srv/lxc/meta/1.0/index-system"))
+        );
+        assert_eq!(resolve_path(root_dir, "/images/../../etc/passwd"), None);
+        assert_eq!(resolve_path(root_dir, "/.store/sha256/ab/abcd"), None);
+        assert_eq!(
+            resolve_path(root_dir, "/images/%2Erepodata_x/rootfs.tar.xz"),
+            None
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::{Context, Result};
+use nix::{
+    fcntl::{fcntl, FcntlArg, SealFlag},
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        socket::{sendmsg, ControlMessage, MsgFlags, UnixAddr},
+    },
+};
+use slog::{Drain, Level, OwnedKVList, Record, KV};
+use std::{
+    env, fmt,
+    fs::File,
+    io::{self, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd},
+        net::UnixDatagram,
+    },
+    sync::OnceLock,
+    time::Duration,
+};
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+struct Notifier {
+    socket: UnixDatagram,
+    watchdog_interval: Option<Duration>,
+}
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+// Stderr is connected to the journal when JOURNAL_STREAM matches its device and inode
+pub fn is_journal_stream() -> bool {
+    let Ok(journal_stream) = env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Ok(stat) = nix::sys::stat::fstat(2) else {
+        return false;
+    };
+
+    journal_stream == format!("{}:{}", stat.st_dev, stat.st_ino)
+}
+
+pub fn is_notify_socket() -> bool {
+    env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    let watchdog_usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    if let Some(watchdog_pid) = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|watchdog_pid| watchdog_pid.parse::<u32>().ok())
+    {
+        if watchdog_pid != std::process::id() {
+            return None;
+        }
+    }
+
+    // Ping twice per watchdog period as recommended by sd_watchdog_enabled(3)
+    Some(Duration::from_micros(watchdog_usec) / 2)
+}
+
+fn connect_notify_socket() -> Result<UnixDatagram> {
+    let notify_socket = env::var("NOTIFY_SOCKET").with_context(|| "NOTIFY_SOCKET is not set")?;
+    let socket = UnixDatagram::unbound()?;
+
+    match notify_socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(abstract_name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.connect_addr(&SocketAddr::from_abstract_name(abstract_name)?)?;
+        }
+        _ => socket.connect(&notify_socket)?,
+    }
+
+    Ok(socket)
+}
+
+pub fn init_notify() -> Result<()> {
+    let notifier = Notifier {
+        socket: connect_notify_socket()
+            .with_context(|| "Failed to connect to systemd notify socket")?,
+        watchdog_interval: watchdog_interval(),
+    };
+
+    let watchdog_interval = notifier.watchdog_interval;
+    let _ = NOTIFIER.set(notifier);
+
+    // Pinged for every command, also serve which has no download loop
+    if let Some(watchdog_interval) = watchdog_interval {
+        tokio::spawn(async move {
+            loop {
+                notify("WATCHDOG=1");
+                tokio::time::sleep(watchdog_interval).await;
+            }
+        });
+    }
+
+    Ok(())
+}
+
+pub fn notify(state: &str) {
+    if let Some(notifier) = NOTIFIER.get() {
+        let _ = notifier.socket.send(state.as_bytes());
+    }
+}
+
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+pub fn notify_status(status: &str) {
+    notify(&format!("STATUS={}", status.replace('\n', " ")));
+}
+
+fn journal_field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+
+    match name {
+        "DIST" | "RELEASE" | "ARCH" | "TYPE" | "NAME" => format!("IMAGE_{}", name),
+        // Fields written by the drain itself must not be overwritten by key-values
+        "MESSAGE" | "MESSAGE_ID" | "PRIORITY" | "SYSLOG_IDENTIFIER" | "SYSLOG_FACILITY"
+        | "SYSLOG_PID" | "CODE_FILE" | "CODE_LINE" | "CODE_MODULE" | "CODE_FUNC" => {
+            format!("FIELD_{}", name)
+        }
+        "" => "FIELD".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn append_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+
+    if value.contains('\n') {
+        // Binary safe encoding of the journal native protocol
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+struct JournalFieldSerializer<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+impl slog::Serializer for JournalFieldSerializer<'_> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        append_field(self.buf, &journal_field_name(key), &val.to_string());
+        Ok(())
+    }
+}
+
+fn journal_priority(level: Level) -> &'static str {
+    match level {
+        Level::Critical => "2",
+        Level::Error => "3",
+        Level::Warning => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+// Drain writing records to journald using the native protocol with slog key-values as fields
+pub struct JournaldDrain {
+    socket: UnixDatagram,
+    identifier: String,
+    level: Level,
+}
+
+impl JournaldDrain {
+    pub fn new(identifier: &str, level: Level) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+
+        Ok(Self {
+            socket,
+            identifier: identifier.to_string(),
+            level,
+        })
+    }
+}
+
+impl JournaldDrain {
+    // Records larger than a datagram are passed as a sealed memfd, like sd_journal_sendv(3) does
+    fn send_memfd(&self, buf: &[u8]) -> io::Result<()> {
+        let fd = memfd_create(
+            c"lxc-tool-journal",
+            MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+        )?;
+        // SAFETY: the descriptor was just created and is owned by nothing else
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(buf)?;
+        fcntl(
+            fd,
+            FcntlArg::F_ADD_SEALS(
+                SealFlag::F_SEAL_SHRINK
+                    | SealFlag::F_SEAL_GROW
+                    | SealFlag::F_SEAL_WRITE
+                    | SealFlag::F_SEAL_SEAL,
+            ),
+        )?;
+
+        sendmsg::<UnixAddr>(
+            self.socket.as_raw_fd(),
+            &[],
+            &[ControlMessage::ScmRights(&[fd])],
+            MsgFlags::empty(),
+            None,
+        )?;
+        Ok(())
+    }
+}
+
+impl Drain for JournaldDrain {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, logger_values: &OwnedKVList) -> io::Result<()> {
+        if !record.level().is_at_least(self.level) {
+            return Ok(());
+        }
+
+        let mut buf = Vec::with_capacity(256);
+        append_field(&mut buf, "MESSAGE", &record.msg().to_string());
+        append_field(&mut buf, "PRIORITY", journal_priority(record.level()));
+        append_field(&mut buf, "SYSLOG_IDENTIFIER", &self.identifier);
+        append_field(&mut buf, "CODE_FILE", record.file());
+        append_field(&mut buf, "CODE_LINE", &record.line().to_string());
+        append_field(&mut buf, "CODE_MODULE", record.module());
+
+        let mut serializer = JournalFieldSerializer { buf: &mut buf };
+        logger_values.serialize(record, &mut serializer)?;
+        record.kv().serialize(record, &mut serializer)?;
+
+        match self.socket.send(&buf) {
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(nix::libc::EMSGSIZE | nix::libc::ENOBUFS)
+                ) =>
+            {
+                self.send_memfd(&buf)
+            }
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append_field, journal_field_name, JournaldDrain};
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, UnixAddr};
+    use slog::Level;
+    use std::{
+        fs::File,
+        io::{IoSliceMut, Read, Seek, SeekFrom},
+        os::unix::{
+            io::{AsRawFd, FromRawFd, RawFd},
+            net::UnixDatagram,
+        },
+    };
+
+    #[test]
+    fn field_name() {
+        assert_eq!(journal_field_name("dist"), "IMAGE_DIST");
+        assert_eq!(journal_field_name("url"), "URL");
+        assert_eq!(journal_field_name("_1bytes-total"), "BYTES_TOTAL");
+        assert_eq!(journal_field_name("message"), "FIELD_MESSAGE");
+        assert_eq!(journal_field_name("priority"), "FIELD_PRIORITY");
+    }
+
+    #[test]
+    fn field_encoding() {
+        let mut buf = vec![];
+        append_field(&mut buf, "MESSAGE", "a=b");
+        append_field(&mut buf, "MESSAGE", "a\nb");
+
+        assert_eq!(buf, b"MESSAGE=a=b\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+
+    #[test]
+    fn memfd_record() {
+        let (socket, journal) = UnixDatagram::pair().unwrap();
+        let drain = JournaldDrain {
+            socket,
+            identifier: "lxc-tool".to_string(),
+            level: Level::Info,
+        };
+        drain.send_memfd(b"MESSAGE=large\n").unwrap();
+
+        let mut buf = [0; 16];
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+        let message = recvmsg::<UnixAddr>(
+            journal.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::empty(),
+        )
+        .unwrap();
+        let Some(ControlMessageOwned::ScmRights(fds)) = message.cmsgs().next() else {
+            panic!("No file descriptor received");
+        };
+
+        // The descriptor shares the file offset left at the end by the sender
+        let mut file = unsafe { File::from_raw_fd(fds[0]) };
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut record = String::new();
+        file.read_to_string(&mut record).unwrap();
+        assert_eq!(record, "MESSAGE=large\n");
+    }
+}